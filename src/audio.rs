@@ -1,8 +1,15 @@
-use std::{fs::File, io::BufWriter, path::Path, sync::atomic::AtomicBool};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use alsa::{
     Direction, Error, ValueOr,
-    pcm::{Access, Format, HwParams, PCM},
+    device_name::HintIter,
+    pcm::{Access, Format, Frames, HwParams, PCM, TstampType},
+};
+use hound::SampleFormat;
+
+use crate::source::{
+    AudioSource, CHUNK_FRAMES, Chunk, ChunkTiming, SourceError, decode_pcm, is_big_endian,
+    pcm_format_spec, unpack_s24_3le,
 };
 
 pub struct CaptureDevice {
@@ -10,6 +17,17 @@ pub struct CaptureDevice {
     channels: u32,
     samplerate: u32,
     format: Format,
+    allow_rate_mismatch: bool,
+}
+
+/// Hardware parameters the device actually agreed to, which can differ from the requested ones.
+#[derive(Debug, Clone, Copy)]
+pub struct HwConfig {
+    pub rate: u32,
+    pub channels: u32,
+    pub format: Format,
+    pub period_size: Frames,
+    pub buffer_size: Frames,
 }
 
 impl CaptureDevice {
@@ -19,10 +37,18 @@ impl CaptureDevice {
             channels,
             samplerate,
             format,
+            allow_rate_mismatch: false,
         }
     }
 
-    fn init_device(&self) -> Result<PCM, Error> {
+    /// Accepts a device rate different from the requested one instead of failing in `open`.
+    /// Samples are then delivered, and recorded, at the rate the hardware picked.
+    pub fn allow_rate_mismatch(mut self, allow: bool) -> Self {
+        self.allow_rate_mismatch = allow;
+        self
+    }
+
+    fn init_device(&self) -> Result<(PCM, HwConfig), Error> {
         let pcm = PCM::new(&self.device_name, Direction::Capture, false)?;
         {
             let hwp = HwParams::any(&pcm)?;
@@ -34,49 +60,382 @@ impl CaptureDevice {
             hwp.set_buffer_size(buf_size)?;
             pcm.hw_params(&hwp)?;
         }
+        {
+            // Timestamps in `status` let gaps be measured and reads be placed in time. They
+            // come from the monotonic clock, which wall clock adjustments do not move.
+            let swp = pcm.sw_params_current()?;
+            swp.set_tstamp_mode(true)?;
+            swp.set_tstamp_type(TstampType::Monotonic)?;
+            pcm.sw_params(&swp)?;
+        }
+        let config = {
+            let hwp = pcm.hw_params_current()?;
+            HwConfig {
+                rate: hwp.get_rate()?,
+                channels: hwp.get_channels()?,
+                format: hwp.get_format()?,
+                period_size: hwp.get_period_size()?,
+                buffer_size: hwp.get_buffer_size()?,
+            }
+        };
+        println!(
+            "ALSA {}: {} Hz, {} ch, {}, period {} frames, buffer {} frames",
+            self.device_name,
+            config.rate,
+            config.channels,
+            config.format,
+            config.period_size,
+            config.buffer_size
+        );
         pcm.prepare()?;
         pcm.start()?;
-        Ok(pcm)
+        Ok((pcm, config))
     }
 
-    pub fn read<P: AsRef<Path>>(&self, output_file: P, running: &AtomicBool) -> Result<(), Error> {
-        let pcm = self.init_device()?;
-        let io = match &self.format {
-            Format::S32LE | Format::S32BE => pcm.io_i32()?,
-            _ => return Err(Error::new("Format unimplemented", 0)),
+    /// Opens and starts the device, returning it as an `AudioSource` whose spec reflects the
+    /// negotiated hardware parameters.
+    pub fn open(&self) -> Result<AlsaSource, SourceError> {
+        if pcm_format_spec(self.format, 1, self.samplerate).is_none() {
+            return Err(Error::new("Format unimplemented", 0).into());
+        }
+        let (pcm, config) = self.init_device()?;
+        if config.rate != self.samplerate && !self.allow_rate_mismatch {
+            return Err(SourceError::Unsupported(format!(
+                "device rate {} Hz (requested {} Hz)",
+                config.rate, self.samplerate
+            )));
+        }
+        if config.channels != self.channels || config.format != self.format {
+            return Err(SourceError::Unsupported(format!(
+                "device configuration {} ch {} (requested {} ch {})",
+                config.channels, config.format, self.channels, self.format
+            )));
+        }
+        let (spec, width) =
+            pcm_format_spec(config.format, config.channels as u16, config.rate).unwrap();
+        let len = CHUNK_FRAMES * config.channels as usize;
+        let big_endian = is_big_endian(config.format);
+        let mut source = AlsaSource {
+            pcm,
+            spec,
+            width,
+            big_endian,
+            int_buf: vec![0; len],
+            short_buf: Vec::new(),
+            float_buf: Vec::new(),
+            byte_buf: Vec::new(),
+            buffer_frames: config.buffer_size as usize,
+            xruns: 0,
+            overrun: false,
+            clock_offset: None,
+            last_read: None,
+            next_time: None,
+            timing: None,
+            silence_frames: 0,
+            silence_time: 0.0,
+            stashed_frames: 0,
+            stashed_time: 0.0,
+            int_silence: Vec::new(),
+            float_silence: Vec::new(),
         };
+        match (spec.sample_format, width) {
+            _ if big_endian => source.byte_buf = vec![0; len * width],
+            (SampleFormat::Float, _) => source.float_buf = vec![0.0; len],
+            (SampleFormat::Int, 2) => source.short_buf = vec![0; len],
+            (SampleFormat::Int, 3) => source.byte_buf = vec![0; len * 3],
+            (SampleFormat::Int, _) => {}
+        }
+        Ok(source)
+    }
+}
 
-        let mut buf = [0i32; 1024 * 32];
-
-        let mut writer = hound::WavWriter::new(
-            BufWriter::new(File::create(output_file).unwrap()),
-            hound::WavSpec {
-                channels: self.channels as u16,
-                sample_rate: self.samplerate,
-                bits_per_sample: 32,
-                sample_format: hound::SampleFormat::Int,
-            },
-        )
-        .unwrap();
-
-        println!("start audio read");
-        while running.load(std::sync::atomic::Ordering::Relaxed) {
-            match io.readi(&mut buf) {
-                Ok(s) => {
-                    let n = s * self.channels as usize;
-                    for sample in &buf[..n] {
-                        writer.write_sample(*sample).unwrap();
-                    }
+pub struct AlsaSource {
+    pcm: PCM,
+    spec: hound::WavSpec,
+    /// Bytes per sample in the negotiated format.
+    width: usize,
+    /// Whether the format is big-endian, read as bytes and swapped.
+    big_endian: bool,
+    int_buf: Vec<i32>,
+    short_buf: Vec<i16>,
+    float_buf: Vec<f32>,
+    byte_buf: Vec<u8>,
+    /// Size of the device buffer.
+    buffer_frames: usize,
+    xruns: u64,
+    /// Set by an overrun until the following read measured the audio lost in it.
+    overrun: bool,
+    /// Wall clock minus device clock in seconds, fixed at the first timestamp.
+    clock_offset: Option<f64>,
+    /// When the previous timestamp was taken, bounding how much audio can have been lost since.
+    last_read: Option<Instant>,
+    /// Expected device time of the next frame.
+    next_time: Option<f64>,
+    timing: Option<ChunkTiming>,
+    /// Frames of silence still to be returned in place of audio lost in an overrun.
+    silence_frames: usize,
+    silence_time: f64,
+    /// Frames already read into the buffers, returned after the silence.
+    stashed_frames: usize,
+    stashed_time: f64,
+    int_silence: Vec<i32>,
+    float_silence: Vec<f32>,
+}
+
+impl AlsaSource {
+    fn readi(&mut self) -> Result<usize, Error> {
+        match (self.spec.sample_format, self.width) {
+            _ if self.big_endian => self.pcm.io_bytes().readi(&mut self.byte_buf),
+            (SampleFormat::Float, _) => self.pcm.io_f32()?.readi(&mut self.float_buf),
+            (SampleFormat::Int, 2) => self.pcm.io_i16()?.readi(&mut self.short_buf),
+            (SampleFormat::Int, 3) => self.pcm.io_bytes().readi(&mut self.byte_buf),
+            (SampleFormat::Int, _) => self.pcm.io_i32()?.readi(&mut self.int_buf),
+        }
+    }
+
+    /// Derives the time of the `frames` just read from the device timestamp and, after an
+    /// overrun, how much audio went missing before them.
+    ///
+    /// Gaps are measured on the device clock and placed on the wall clock through an offset
+    /// taken once, so setting the system time neither fakes nor hides a gap. A gap longer than
+    /// the time since the previous read plus a buffer of audio cannot be real and is only
+    /// reported.
+    fn update_timing(&mut self, frames: usize) -> Result<(), Error> {
+        let status = self.pcm.status()?;
+        let stamp = status.get_htstamp();
+        let now = Instant::now();
+        if stamp.tv_sec == 0 && stamp.tv_nsec == 0 {
+            self.timing = None;
+            return Ok(());
+        }
+        let stamp = stamp.tv_sec as f64 + stamp.tv_nsec as f64 * 1e-9;
+        let offset = *self.clock_offset.get_or_insert_with(|| {
+            let wall = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            wall.as_secs_f64() - stamp
+        });
+        let rate = self.spec.sample_rate as f64;
+        // The timestamp is taken with `avail` frames still waiting behind the ones just read.
+        let queued = status.get_avail() as f64 + frames as f64;
+        let time = stamp - queued / rate;
+        self.timing = Some(ChunkTiming {
+            time: time + offset,
+            silence: false,
+        });
+        if self.overrun
+            && let Some(expected) = self.next_time
+        {
+            let lost = ((time - expected) * rate).round();
+            let limit = self.last_read.map_or(0.0, |last| {
+                (now - last).as_secs_f64() * rate + self.buffer_frames as f64
+            });
+            if lost > limit {
+                println!("ALSA overrun with an implausible gap of {lost} frames, ignoring it");
+            } else if lost > 0.0 {
+                println!("ALSA overrun lost {lost} frames, inserting silence");
+                self.silence_frames = lost as usize;
+                self.silence_time = expected + offset;
+                self.stashed_time = time + offset;
+            }
+        }
+        self.overrun = false;
+        self.last_read = Some(now);
+        self.next_time = Some(time + frames as f64 / rate);
+        Ok(())
+    }
+
+    /// Returns the next piece of the silence standing in for lost audio.
+    fn silence(&mut self) -> Chunk<'_> {
+        let frames = self.silence_frames.min(CHUNK_FRAMES);
+        self.silence_frames -= frames;
+        self.timing = Some(ChunkTiming {
+            time: self.silence_time,
+            silence: true,
+        });
+        self.silence_time += frames as f64 / self.spec.sample_rate as f64;
+        let n = frames * self.spec.channels as usize;
+        match self.spec.sample_format {
+            SampleFormat::Float => {
+                self.float_silence.resize(n, 0.0);
+                Chunk::Float(&self.float_silence[..n])
+            }
+            SampleFormat::Int => {
+                self.int_silence.resize(n, 0);
+                Chunk::Int(&self.int_silence[..n])
+            }
+        }
+    }
+
+    /// Returns the first `frames` frames in the read buffers as a chunk.
+    fn chunk(&mut self, frames: usize) -> Chunk<'_> {
+        let n = frames * self.spec.channels as usize;
+        match (self.spec.sample_format, self.width) {
+            _ if self.big_endian => decode_pcm(
+                &self.byte_buf[..n * self.width],
+                self.spec,
+                self.width,
+                true,
+                &mut self.int_buf,
+                &mut self.float_buf,
+            ),
+            (SampleFormat::Float, _) => Chunk::Float(&self.float_buf[..n]),
+            (SampleFormat::Int, 2) => {
+                self.int_buf.clear();
+                self.int_buf
+                    .extend(self.short_buf[..n].iter().map(|s| *s as i32));
+                Chunk::Int(&self.int_buf)
+            }
+            (SampleFormat::Int, 3) => {
+                unpack_s24_3le(&self.byte_buf[..n * 3], &mut self.int_buf);
+                Chunk::Int(&self.int_buf)
+            }
+            (SampleFormat::Int, _) => {
+                // S24_LE keeps 24 significant bits in the low bytes of a 32-bit container.
+                let shift = 32 - self.spec.bits_per_sample;
+                if shift > 0 {
+                    self.int_buf[..n]
+                        .iter_mut()
+                        .for_each(|s| *s = (*s << shift) >> shift);
                 }
+                Chunk::Int(&self.int_buf[..n])
+            }
+        }
+    }
+}
+
+impl AudioSource for AlsaSource {
+    fn spec(&self) -> hound::WavSpec {
+        self.spec
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn xruns(&self) -> u64 {
+        self.xruns
+    }
+
+    fn timing(&self) -> Option<ChunkTiming> {
+        self.timing
+    }
+
+    fn read(&mut self) -> Result<Option<Chunk<'_>>, SourceError> {
+        if self.silence_frames > 0 {
+            return Ok(Some(self.silence()));
+        }
+        if self.stashed_frames > 0 {
+            let frames = std::mem::take(&mut self.stashed_frames);
+            self.timing = Some(ChunkTiming {
+                time: self.stashed_time,
+                silence: false,
+            });
+            return Ok(Some(self.chunk(frames)));
+        }
+        let frames = loop {
+            match self.readi() {
+                Ok(s) if s > 0 => break s,
+                Ok(_) => {}
+                Err(err) if err.errno() == 11 => {}
                 Err(err) => {
-                    if err.errno() != 11 {
+                    // EPIPE is an overrun: the buffer filled up before it was read.
+                    if err.errno() == 32 {
+                        self.xruns += 1;
+                        self.overrun = true;
+                    } else {
                         println!("ALSA try recover from: {err}");
-                        pcm.try_recover(err, false)?;
                     }
+                    self.pcm.try_recover(err, false)?;
                 }
             }
-            // thread::sleep(Duration::from_millis(1).saturating_sub(start.elapsed()));
+        };
+
+        self.update_timing(frames)?;
+        if self.silence_frames > 0 {
+            self.stashed_frames = frames;
+            return Ok(Some(self.silence()));
+        }
+        Ok(Some(self.chunk(frames)))
+    }
+}
+
+/// Formats `CaptureDevice` knows how to read, in the order they are reported.
+const CAPTURE_FORMATS: [Format; 8] = [
+    Format::S16LE,
+    Format::S16BE,
+    Format::S243LE,
+    Format::S24LE,
+    Format::S32LE,
+    Format::S32BE,
+    Format::FloatLE,
+    Format::FloatBE,
+];
+
+/// Rates probed individually since most devices only support a few discrete ones.
+const COMMON_RATES: [u32; 8] = [8000, 16000, 22050, 32000, 44100, 48000, 96000, 192000];
+
+/// Prints the capture parameters a PCM accepts.
+fn print_capture_caps(name: &str) -> Result<(), Error> {
+    // Non-blocking so a device that is busy elsewhere is reported instead of hanging.
+    let pcm = PCM::new(name, Direction::Capture, true)?;
+    let hwp = HwParams::any(&pcm)?;
+    let rates = COMMON_RATES
+        .iter()
+        .filter(|r| hwp.test_rate(**r).is_ok())
+        .map(|r| r.to_string())
+        .collect::<Vec<_>>();
+    let formats = CAPTURE_FORMATS
+        .iter()
+        .filter(|f| hwp.test_format(**f).is_ok())
+        .map(|f| f.to_string())
+        .collect::<Vec<_>>();
+    println!(
+        "    rates: {}-{} Hz ({})",
+        hwp.get_rate_min()?,
+        hwp.get_rate_max()?,
+        rates.join(", ")
+    );
+    println!(
+        "    channels: {}-{}",
+        hwp.get_channels_min()?,
+        hwp.get_channels_max()?
+    );
+    println!("    formats: {}", formats.join(", "));
+    Ok(())
+}
+
+/// Prints every sound card and the capture PCMs ALSA knows about, with their supported rates,
+/// channel ranges and formats.
+pub fn list_devices() -> Result<(), Error> {
+    println!("Cards:");
+    for card in alsa::card::Iter::new() {
+        let card = card?;
+        println!(
+            "  {}: {} ({})",
+            card.get_index(),
+            card.get_name()?,
+            card.get_longname()?
+        );
+    }
+
+    println!("Capture PCMs:");
+    for hint in HintIter::new_str(None, "pcm")? {
+        if hint.direction == Some(Direction::Playback) {
+            continue;
+        }
+        let Some(name) = hint.name else {
+            continue;
+        };
+        println!("  {name}");
+        if let Some(desc) = hint.desc {
+            for line in desc.lines() {
+                println!("    {line}");
+            }
+        }
+        if let Err(err) = print_capture_caps(&name) {
+            println!("    unavailable: {err}");
         }
-        Ok(())
     }
+    Ok(())
 }
@@ -0,0 +1,338 @@
+//! Broadcast WAV metadata: the EBU `bext` chunk for when a recording was made and an `iXML`
+//! chunk for where and with what.
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hound::WavSpec;
+
+use crate::timestamp::Timestamp;
+
+/// Size of the `bext` chunk body without coding history.
+const BEXT_LEN: usize = 602;
+
+/// What is known about how a recording was made.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Program or organisation that made the recording.
+    pub originator: String,
+    /// UTC start of the recording.
+    pub origination: Option<Timestamp>,
+    /// Frames between midnight of the origination date and the first frame.
+    pub time_reference: u64,
+    pub device: String,
+    pub node_id: String,
+    /// Name of each channel in interleaving order.
+    pub channel_names: Vec<String>,
+    pub gain_db: Option<f32>,
+    /// Latitude and longitude in degrees.
+    pub gps: Option<(f64, f64)>,
+}
+
+impl Metadata {
+    /// Whether nothing is known, e.g. for files without `bext` and `iXML` chunks.
+    pub fn is_empty(&self) -> bool {
+        self.originator.is_empty()
+            && self.origination.is_none()
+            && self.device.is_empty()
+            && self.node_id.is_empty()
+            && self.channel_names.is_empty()
+            && self.gain_db.is_none()
+            && self.gps.is_none()
+    }
+
+    /// Copy of `self` for a recording whose first frame was captured at `start`.
+    pub fn starting_at(&self, start: SystemTime, sample_rate: u32) -> Self {
+        let since_epoch = start.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let rate = sample_rate as u64;
+        Self {
+            origination: Some(Timestamp::from_system_time(start)),
+            time_reference: since_epoch.as_secs() % 86400 * rate
+                + since_epoch.subsec_nanos() as u64 * rate / 1_000_000_000,
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(start) = &self.origination {
+            writeln!(
+                f,
+                "recorded {} {} UTC (time reference {})",
+                start.iso_date(),
+                start.iso_time(),
+                self.time_reference
+            )?;
+        }
+        for (name, value) in [
+            ("originator", &self.originator),
+            ("device", &self.device),
+            ("node", &self.node_id),
+        ] {
+            if !value.is_empty() {
+                writeln!(f, "{name}: {value}")?;
+            }
+        }
+        if !self.channel_names.is_empty() {
+            writeln!(f, "channels: {}", self.channel_names.join(", "))?;
+        }
+        if let Some(gain) = self.gain_db {
+            writeln!(f, "gain: {gain} dB")?;
+        }
+        if let Some((lat, lon)) = self.gps {
+            writeln!(f, "location: {lat}, {lon}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends a NUL to odd-sized chunk bodies, since some readers ignore RIFF pad bytes.
+fn pad_even(body: &mut Vec<u8>) {
+    if body.len() % 2 == 1 {
+        body.push(0);
+    }
+}
+
+/// Copies `value` into a fixed-size, zero-padded ASCII field.
+fn put_field(out: &mut Vec<u8>, value: &str, len: usize) {
+    let bytes = &value.as_bytes()[..value.len().min(len)];
+    out.extend_from_slice(bytes);
+    out.resize(out.len() + len - bytes.len(), 0);
+}
+
+fn get_field(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_owned()
+}
+
+/// Body of the version 1 `bext` chunk.
+pub fn bext_chunk(meta: &Metadata, spec: &WavSpec) -> Vec<u8> {
+    let mut bext = Vec::with_capacity(BEXT_LEN + 64);
+    let description = match meta.node_id.as_str() {
+        "" => String::new(),
+        node => format!("node {node}"),
+    };
+    put_field(&mut bext, &description, 256);
+    put_field(&mut bext, &meta.originator, 32);
+    put_field(&mut bext, &meta.node_id, 32);
+    let (date, time) = match &meta.origination {
+        Some(start) => (start.iso_date(), start.iso_time()),
+        None => (String::new(), String::new()),
+    };
+    put_field(&mut bext, &date, 10);
+    put_field(&mut bext, &time, 8);
+    bext.extend_from_slice(&meta.time_reference.to_le_bytes());
+    bext.extend_from_slice(&1u16.to_le_bytes());
+    // UMID and reserved space.
+    bext.resize(BEXT_LEN, 0);
+    let mode = match spec.channels {
+        1 => "mono",
+        2 => "stereo",
+        _ => "multichannel",
+    };
+    bext.extend_from_slice(
+        format!(
+            "A=PCM,F={},W={},M={mode}\r\n",
+            spec.sample_rate, spec.bits_per_sample
+        )
+        .as_bytes(),
+    );
+    pad_even(&mut bext);
+    bext
+}
+
+/// Reads the origination fields of a `bext` chunk into `meta`.
+pub fn parse_bext(bext: &[u8], meta: &mut Metadata) {
+    if bext.len() < 346 {
+        return;
+    }
+    meta.originator = get_field(&bext[256..288]);
+    if meta.node_id.is_empty() {
+        meta.node_id = get_field(&bext[288..320]);
+    }
+    meta.origination = Timestamp::parse(&get_field(&bext[320..330]), &get_field(&bext[330..338]));
+    meta.time_reference = u64::from_le_bytes(bext[338..346].try_into().unwrap());
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Body of the `iXML` chunk.
+pub fn ixml_chunk(meta: &Metadata) -> Vec<u8> {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<BWFXML>\n");
+    xml += "  <IXML_VERSION>2.10</IXML_VERSION>\n";
+    xml += &format!("  <DEVICE>{}</DEVICE>\n", escape(&meta.device));
+    xml += &format!("  <NODE_ID>{}</NODE_ID>\n", escape(&meta.node_id));
+    if let Some(gain) = meta.gain_db {
+        xml += &format!("  <GAIN_DB>{gain}</GAIN_DB>\n");
+    }
+    xml += "  <TRACK_LIST>\n";
+    xml += &format!(
+        "    <TRACK_COUNT>{}</TRACK_COUNT>\n",
+        meta.channel_names.len()
+    );
+    for (i, name) in meta.channel_names.iter().enumerate() {
+        xml += &format!(
+            "    <TRACK><CHANNEL_INDEX>{0}</CHANNEL_INDEX><INTERLEAVE_INDEX>{0}</INTERLEAVE_INDEX><NAME>{1}</NAME></TRACK>\n",
+            i + 1,
+            escape(name)
+        );
+    }
+    xml += "  </TRACK_LIST>\n";
+    if let Some((lat, lon)) = meta.gps {
+        xml += &format!("  <LOCATION><LOCATION_GPS>{lat}, {lon}</LOCATION_GPS></LOCATION>\n");
+    }
+    xml += "</BWFXML>\n";
+    let mut ixml = xml.into_bytes();
+    pad_even(&mut ixml);
+    ixml
+}
+
+/// Texts of all `<tag>` elements in `xml`.
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        found.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    found
+}
+
+fn element(xml: &str, tag: &str) -> Option<String> {
+    elements(xml, tag).first().map(|text| unescape(text.trim()))
+}
+
+/// Reads the fields of an `iXML` chunk written by `ixml_chunk` into `meta`.
+pub fn parse_ixml(ixml: &[u8], meta: &mut Metadata) {
+    let xml = String::from_utf8_lossy(ixml);
+    if let Some(device) = element(&xml, "DEVICE") {
+        meta.device = device;
+    }
+    if let Some(node_id) = element(&xml, "NODE_ID") {
+        meta.node_id = node_id;
+    }
+    meta.gain_db = element(&xml, "GAIN_DB").and_then(|gain| gain.parse().ok());
+    meta.channel_names = elements(&xml, "NAME")
+        .into_iter()
+        .map(|name| unescape(name.trim()))
+        .collect();
+    meta.gps = element(&xml, "LOCATION_GPS").and_then(|gps| parse_gps(&gps));
+}
+
+/// Parses `"<latitude>, <longitude>"` in degrees.
+pub fn parse_gps(s: &str) -> Option<(f64, f64)> {
+    let (lat, lon) = s.split_once(',')?;
+    let lat: f64 = lat.trim().parse().ok()?;
+    let lon: f64 = lon.trim().parse().ok()?;
+    ((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)).then_some((lat, lon))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use hound::SampleFormat;
+
+    use super::*;
+    use crate::wav::{WavWriter, read_header};
+
+    fn spec() -> WavSpec {
+        WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 24,
+            sample_format: SampleFormat::Int,
+        }
+    }
+
+    fn metadata() -> Metadata {
+        let start = UNIX_EPOCH + Duration::from_secs_f64(1_700_000_000.25);
+        Metadata {
+            originator: "detection_test".to_owned(),
+            device: "hw:1,0".to_owned(),
+            node_id: "node <7> & co".to_owned(),
+            channel_names: vec!["north".to_owned(), "south".to_owned()],
+            gain_db: Some(-6.5),
+            gps: Some((52.2297, -21.0122)),
+            ..Metadata::default()
+        }
+        .starting_at(start, spec().sample_rate)
+    }
+
+    #[test]
+    fn bext_has_the_fixed_layout_and_coding_history() {
+        let bext = bext_chunk(&metadata(), &spec());
+        assert_eq!(&bext[320..338], b"2023-11-1422:13:20");
+        assert_eq!(&bext[346..348], &1u16.to_le_bytes());
+        assert!(bext[348..BEXT_LEN].iter().all(|b| *b == 0));
+        assert!(bext[BEXT_LEN..].starts_with(b"A=PCM,F=48000,W=24,M=stereo\r\n"));
+        assert_eq!(bext.len() % 2, 0);
+    }
+
+    #[test]
+    fn metadata_survives_a_wav_round_trip() {
+        let written = metadata();
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(&mut file, spec(), Some(&written)).unwrap();
+        writer.write_int(&[0; 8]).unwrap();
+        writer.finalize().unwrap();
+
+        let bytes = file.into_inner();
+        let at = bytes.windows(4).position(|id| id == b"bext").unwrap();
+        let len = u32::from_le_bytes(bytes[at + 4..at + 8].try_into().unwrap()) as usize;
+        assert_eq!(len, bext_chunk(&written, &spec()).len());
+        assert!(len >= BEXT_LEN);
+
+        let read = read_header(&mut Cursor::new(bytes)).unwrap().metadata;
+        let start = read.origination.unwrap();
+        assert_eq!(start.iso_date(), "2023-11-14");
+        assert_eq!(start.iso_time(), "22:13:20");
+        assert_eq!(read.time_reference, 80_000 * 48_000 + 12_000);
+        assert_eq!(read.originator, written.originator);
+        assert_eq!(read.device, written.device);
+        assert_eq!(read.node_id, written.node_id);
+        assert_eq!(read.channel_names, written.channel_names);
+        assert_eq!(read.gain_db, written.gain_db);
+        assert_eq!(read.gps, written.gps);
+    }
+
+    #[test]
+    fn files_without_metadata_read_as_empty() {
+        let mut file = Cursor::new(Vec::new());
+        WavWriter::new(&mut file, spec(), None)
+            .unwrap()
+            .finalize()
+            .unwrap();
+        let info = read_header(&mut Cursor::new(file.into_inner())).unwrap();
+        assert!(info.metadata.is_empty());
+    }
+
+    #[test]
+    fn parses_gps_coordinates() {
+        assert_eq!(parse_gps("52.2297, 21.0122"), Some((52.2297, 21.0122)));
+        assert_eq!(parse_gps("-33.9,-180"), Some((-33.9, -180.0)));
+        assert_eq!(parse_gps(" 90 , 0 "), Some((90.0, 0.0)));
+        for invalid in ["", "52.2", "91,0", "0,180.5", "north,east", "1,2,3"] {
+            assert_eq!(parse_gps(invalid), None, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use circular_buffer::CircularBuffer;
+use hound::{SampleFormat, WavSpec};
+
+use crate::{recorder, timestamp::Timestamp, wav::WavWriter};
+
+/// Upper bound on the frames kept before a detection.
+pub const MAX_PRE_FRAMES: usize = 1024;
+
+/// What the sidecar lists for a frame.
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Label and probability from the model, `None` if inference failed, the features were
+    /// still warming up or the frame was dropped.
+    prediction: Option<(i64, f32)>,
+    detected: bool,
+    /// The frame never reached the model and silence stands in for its samples.
+    dropped: bool,
+}
+
+struct Frame {
+    /// Samples the frame adds to the stream.
+    samples: Vec<i32>,
+    entry: Entry,
+}
+
+struct Clip {
+    writer: WavWriter<BufWriter<File>>,
+    path: PathBuf,
+    /// Position of the first sample in the stream, in frames of the source.
+    start: u64,
+    frames: Vec<Entry>,
+    /// Frames still to be written before the clip is closed.
+    remaining: usize,
+}
+
+/// Saves the audio around detections: keeps the last frames in memory and, once the detector
+/// fires, writes them plus the frames that follow to a WAV clip with a JSON sidecar holding
+/// the per-frame predictions. A detection while a clip is open extends it. Frames dropped
+/// before inference are saved as silence and listed as gaps in the sidecar.
+///
+/// Frames may overlap. Only the samples each frame adds to the stream are saved, so a clip
+/// holds every sample once.
+///
+/// Clips are named after the time they start and numbered in order, and never replace
+/// existing files.
+pub struct ClipRecorder {
+    dir: PathBuf,
+    spec: WavSpec,
+    hop_len: usize,
+    frame_seconds: f64,
+    pre_frames: usize,
+    post_frames: usize,
+    history: Box<CircularBuffer<MAX_PRE_FRAMES, Frame>>,
+    clip: Option<Clip>,
+    /// Clips started so far.
+    count: u32,
+    /// Source frames passed so far, counting dropped ones.
+    position: u64,
+}
+
+impl ClipRecorder {
+    /// `spec` is the layout of the source, `hop_len` the number of interleaved samples from
+    /// the start of one frame passed to `push` to the next.
+    pub fn new(
+        dir: PathBuf,
+        spec: WavSpec,
+        hop_len: usize,
+        pre_seconds: f64,
+        post_seconds: f64,
+    ) -> io::Result<Self> {
+        let frame_seconds = hop_len as f64 / spec.channels as f64 / spec.sample_rate as f64;
+        let pre_frames = (pre_seconds / frame_seconds).ceil() as usize;
+        if pre_frames > MAX_PRE_FRAMES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "pre-trigger time is limited to {:.1} s",
+                    MAX_PRE_FRAMES as f64 * frame_seconds
+                ),
+            ));
+        }
+        fs::create_dir_all(&dir)?;
+        // Frames hold samples scaled to full-scale 32-bit integers.
+        let spec = WavSpec {
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Int,
+            ..spec
+        };
+        Ok(Self {
+            dir,
+            spec,
+            hop_len,
+            frame_seconds,
+            pre_frames,
+            post_frames: ((post_seconds / frame_seconds).ceil() as usize).max(1),
+            history: CircularBuffer::boxed(),
+            clip: None,
+            count: 0,
+            position: 0,
+        })
+    }
+
+    /// Adds the next frame together with the model output for it and whether it counts as a
+    /// detection.
+    pub fn push(
+        &mut self,
+        mut samples: Vec<i32>,
+        prediction: Option<(i64, f32)>,
+        detected: bool,
+    ) -> io::Result<()> {
+        samples.drain(..samples.len().saturating_sub(self.hop_len));
+        self.add(Frame {
+            samples,
+            entry: Entry {
+                prediction,
+                detected,
+                dropped: false,
+            },
+        })
+    }
+
+    /// Accounts for `frames` frames that were dropped before reaching the model, so the
+    /// audio that follows stays in place.
+    pub fn skip(&mut self, frames: usize) -> io::Result<()> {
+        for _ in 0..frames {
+            self.add(Frame {
+                samples: vec![0; self.hop_len],
+                entry: Entry {
+                    prediction: None,
+                    detected: false,
+                    dropped: true,
+                },
+            })?;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, frame: Frame) -> io::Result<()> {
+        let detected = frame.entry.detected;
+        let position = self.position;
+        self.position += (frame.samples.len() / self.spec.channels as usize) as u64;
+        if self.clip.is_none() {
+            if !detected {
+                if self.pre_frames > 0 {
+                    if self.history.len() == self.pre_frames {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(frame);
+                }
+                return Ok(());
+            }
+            self.start(position)?;
+        }
+
+        let clip = self.clip.as_mut().unwrap();
+        clip.writer.write_int(&frame.samples)?;
+        clip.frames.push(frame.entry);
+        if detected {
+            clip.remaining = self.post_frames;
+        } else {
+            clip.remaining -= 1;
+        }
+        if clip.remaining == 0 {
+            self.close()?;
+        }
+        Ok(())
+    }
+
+    /// Closes the clip in progress, if any.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.close()
+    }
+
+    /// Opens a clip holding the frames in history, followed by the frame at `position`.
+    fn start(&mut self, position: u64) -> io::Result<()> {
+        self.count += 1;
+        let now = Timestamp::now();
+        let name = format!(
+            "{}_{}_{:04}",
+            now.compact_date(),
+            now.compact_time(),
+            self.count
+        );
+        let (path, file) = recorder::create_new(&self.dir, &name, "wav", &["json"])?;
+        let history = self.history.iter().map(|f| f.samples.len()).sum::<usize>();
+        let mut clip = Clip {
+            writer: WavWriter::new(BufWriter::new(file), self.spec, None)?,
+            path,
+            start: position - (history / self.spec.channels as usize) as u64,
+            frames: Vec::new(),
+            remaining: self.post_frames,
+        };
+        for frame in self.history.drain(..) {
+            clip.writer.write_int(&frame.samples)?;
+            clip.frames.push(frame.entry);
+        }
+        println!("detection, saving clip {}", clip.path.display());
+        self.clip = Some(clip);
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        let Some(Clip {
+            writer,
+            path,
+            start,
+            frames,
+            ..
+        }) = self.clip.take()
+        else {
+            return Ok(());
+        };
+        writer.finalize()?;
+        self.write_sidecar(&path, start, &frames)?;
+        println!(
+            "finished clip {} ({:.1} s)",
+            path.display(),
+            frames.len() as f64 * self.frame_seconds
+        );
+        Ok(())
+    }
+
+    /// Writes `<clip>.json` listing the prediction for every frame of the clip, with `offset`
+    /// being where the samples the frame adds start, in seconds from the beginning of the clip.
+    /// `start` is where the clip begins in the stream, in seconds from its first sample.
+    /// Runs of dropped frames are listed under `gaps`, each starting at `sample`, counted in
+    /// frames from the beginning of the clip, and `samples` long.
+    fn write_sidecar(&self, path: &Path, start: u64, frames: &[Entry]) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path.with_extension("json"))?;
+        let mut json = BufWriter::new(file);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        writeln!(json, "{{")?;
+        writeln!(json, "  \"audio\": \"{}\",", escape(&name))?;
+        writeln!(
+            json,
+            "  \"start\": {:.4},",
+            start as f64 / self.spec.sample_rate as f64
+        )?;
+        writeln!(json, "  \"sample_rate\": {},", self.spec.sample_rate)?;
+        writeln!(json, "  \"channels\": {},", self.spec.channels)?;
+        writeln!(json, "  \"frame_seconds\": {},", self.frame_seconds)?;
+        writeln!(json, "  \"frames\": [")?;
+        for (i, entry) in frames.iter().enumerate() {
+            let detected = entry.detected;
+            let (label, probability) = match entry.prediction {
+                Some((label, probability)) => (label.to_string(), probability.to_string()),
+                None => ("null".to_owned(), "null".to_owned()),
+            };
+            let separator = if i + 1 < frames.len() { "," } else { "" };
+            writeln!(
+                json,
+                "    {{\"offset\": {:.4}, \"label\": {label}, \"probability\": {probability}, \"detected\": {detected}}}{separator}",
+                i as f64 * self.frame_seconds
+            )?;
+        }
+        writeln!(json, "  ],")?;
+
+        let hop_frames = self.hop_len / self.spec.channels as usize;
+        let mut gaps = Vec::new();
+        for (i, entry) in frames.iter().enumerate() {
+            match gaps.last_mut() {
+                Some((start, len)) if entry.dropped && *start + *len == i => *len += 1,
+                _ if entry.dropped => gaps.push((i, 1)),
+                _ => {}
+            }
+        }
+        writeln!(json, "  \"gaps\": [")?;
+        for (i, (start, len)) in gaps.iter().enumerate() {
+            let separator = if i + 1 < gaps.len() { "," } else { "" };
+            writeln!(
+                json,
+                "    {{\"offset\": {:.4}, \"sample\": {}, \"samples\": {}}}{separator}",
+                *start as f64 * self.frame_seconds,
+                start * hop_frames,
+                len * hop_frames
+            )?;
+        }
+        writeln!(json, "  ]")?;
+        writeln!(json, "}}")?;
+        json.flush()
+    }
+}
+
+/// Escapes `s` for use inside a JSON string.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> WavSpec {
+        WavSpec {
+            channels: 2,
+            sample_rate: 8,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("clips-{}-{name}", std::process::id()))
+    }
+
+    /// Files in `dir` with `extension`, sorted by name.
+    fn files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == extension))
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn clips_in_the_same_second_keep_their_own_files() {
+        let dir = temp_dir("same-second");
+        let mut clips = ClipRecorder::new(dir.clone(), spec(), 8, 0.5, 0.5).unwrap();
+        for detected in [false, true, false, false, true, false] {
+            clips.push(vec![1; 8], Some((1, 0.9)), detected).unwrap();
+        }
+        clips.finish().unwrap();
+
+        let wavs = files(&dir, "wav");
+        let sidecars = files(&dir, "json");
+        assert_eq!(wavs.len(), 2);
+        assert_eq!(sidecars.len(), 2);
+        let first = fs::read_to_string(&sidecars[0]).unwrap();
+        let second = fs::read_to_string(&sidecars[1]).unwrap();
+        assert!(first.contains("\"start\": 0.0000,"));
+        assert!(second.contains("\"start\": 1.5000,"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dropped_frames_become_silence_and_gaps() {
+        let dir = temp_dir("gaps");
+        let mut clips = ClipRecorder::new(dir.clone(), spec(), 8, 0.0, 2.0).unwrap();
+        clips.push(vec![1; 8], Some((1, 0.9)), true).unwrap();
+        clips.skip(2).unwrap();
+        clips.push(vec![1; 8], Some((1, 0.8)), true).unwrap();
+        clips.finish().unwrap();
+
+        let json = fs::read_to_string(&files(&dir, "json")[0]).unwrap();
+        assert!(
+            json.contains(
+                "\"gaps\": [\n    {\"offset\": 0.5000, \"sample\": 4, \"samples\": 8}\n  ]"
+            )
+        );
+        assert_eq!(json.matches("\"label\": null").count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,638 @@
+//! Settings of the spectral features. A model only works with features computed the way its
+//! training data was, so `gen-csv` writes the settings next to every CSV and models can carry
+//! them in their ONNX metadata under `METADATA_KEY`, as the JSON written by `to_json`.
+
+use std::{fmt, fs, io, path::Path};
+
+use crate::models::SAMPLE_RATE;
+
+/// ONNX metadata property holding the feature settings of a model.
+pub const METADATA_KEY: &str = "feature_config";
+
+/// Largest FFT the spectrum analyzer can compute.
+pub const MAX_FFT_LEN: usize = 32768;
+/// Most points a frequency grid may have.
+const MAX_GRID_LEN: usize = 100_000;
+
+/// Feature vector computed from the spectrum of each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// FFT magnitudes minus their moving average, isolating tonal peaks.
+    Spectrum,
+    /// Energies of mel-spaced triangular bands, see `mel_*`.
+    LogMel,
+    /// Cepstral coefficients of the log-mel energies.
+    Mfcc,
+    /// Fundamental, harmonic count and harmonic-to-noise ratio of the strongest harmonic
+    /// series, see `*_fundamental` and `*_harmonic*`.
+    Harmonic,
+    /// Spectral centroid, bandwidth, flatness, rolloff, crest factor, kurtosis, band energies
+    /// and RMS, a compact vector for small models, see `rolloff` and `energy_bands`.
+    Summary,
+}
+
+/// Compression applied to the mel band energies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Log {
+    /// Decibels, `10 log10`.
+    Db,
+    Natural,
+    None,
+}
+
+/// Window applied to each frame before the FFT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Hann,
+    Hamming,
+    /// 4-term Blackman-Harris, for low leakage far from a peak.
+    BlackmanHarris,
+    /// Flat-top, for accurate peak amplitudes at the cost of resolution.
+    FlatTop,
+    /// Kaiser with the shape set by `kaiser_beta`.
+    Kaiser,
+}
+
+/// How the smoothed spectrum difference is scaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    /// Linearly onto `[-1, 1]` from the minimum and maximum of the frame.
+    MinMax,
+    None,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Spectrum => write!(f, "spectrum"),
+            Kind::LogMel => write!(f, "log-mel"),
+            Kind::Mfcc => write!(f, "mfcc"),
+            Kind::Harmonic => write!(f, "harmonic"),
+            Kind::Summary => write!(f, "summary"),
+        }
+    }
+}
+
+impl fmt::Display for Log {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Log::Db => write!(f, "db"),
+            Log::Natural => write!(f, "ln"),
+            Log::None => write!(f, "none"),
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Window::Hann => write!(f, "hann"),
+            Window::Hamming => write!(f, "hamming"),
+            Window::BlackmanHarris => write!(f, "blackman-harris"),
+            Window::FlatTop => write!(f, "flat-top"),
+            Window::Kaiser => write!(f, "kaiser"),
+        }
+    }
+}
+
+impl fmt::Display for Scaling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scaling::MinMax => write!(f, "min-max"),
+            Scaling::None => write!(f, "none"),
+        }
+    }
+}
+
+/// Everything that decides the feature values computed from a recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureConfig {
+    pub kind: Kind,
+    /// Rate inputs are resampled to before framing.
+    pub sample_rate: u32,
+    /// Samples per channel in each frame.
+    pub frame_len: usize,
+    /// Samples per channel from the start of one frame to the next.
+    pub hop: usize,
+    /// Frequency range of the spectrum in Hz, also the ends of the grid.
+    pub min_freq: f32,
+    pub max_freq: f32,
+    pub window: Window,
+    /// Shape of the Kaiser window, wider main lobe and lower side lobes as it grows.
+    pub kaiser_beta: f32,
+    /// Samples the windowed frame is zero-padded to before the FFT, the frame length rounded
+    /// up to a power of two if not set.
+    pub fft_len: Option<usize>,
+    /// Length of the moving average subtracted from the spectrum.
+    pub smoothing_taps: usize,
+    pub scaling: Scaling,
+    /// Mel bands spread evenly on the mel scale between `min_freq` and `max_freq`.
+    pub mel_bands: usize,
+    /// Exponent of the FFT magnitudes summed into the bands, 2 for power and 1 for magnitude.
+    pub mel_power: f32,
+    /// Compression of the band energies, which MFCCs need.
+    pub mel_log: Log,
+    /// Cepstral coefficients kept, counting the 0th.
+    pub mfcc_coefficients: usize,
+    /// Range in Hz the fundamental of a harmonic series is searched in, within `min_freq` to
+    /// `max_freq`.
+    pub min_fundamental: f32,
+    pub max_fundamental: f32,
+    /// Harmonics looked at, counting the fundamental.
+    pub max_harmonics: usize,
+    /// Level in dB above the background at which a harmonic counts as present.
+    pub harmonic_threshold: f32,
+    /// Share of the energy below the spectral rolloff frequency.
+    pub rolloff: f32,
+    /// Logarithmically spaced bands the energy is reported in by summary features.
+    pub energy_bands: usize,
+    /// Orders of differences over time appended to each vector: 1 for deltas, 2 for deltas
+    /// and delta-deltas. They are centred on their frame, so the vectors lag the audio by
+    /// `deltas * delta_width` frames.
+    pub deltas: usize,
+    /// Frames on each side of the regression the deltas are estimated by.
+    pub delta_width: usize,
+    /// Feature vectors of consecutive frames, deltas included, stacked oldest first into each
+    /// row of a CSV or model input, for models that learn how the sound changes over time.
+    pub context_frames: usize,
+    /// Spacing in Hz of a fixed grid from `min_freq` to `max_freq` the features are
+    /// interpolated onto, so their length does not depend on the FFT length or sample rate.
+    /// The FFT bins are used as they are if not set. Spectrum features only.
+    pub grid_step: Option<f32>,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            kind: Kind::Spectrum,
+            sample_rate: SAMPLE_RATE,
+            frame_len: 8192,
+            hop: 8192,
+            min_freq: 5.0,
+            max_freq: 4000.0,
+            window: Window::Hann,
+            kaiser_beta: 8.6,
+            fft_len: None,
+            smoothing_taps: 21,
+            scaling: Scaling::MinMax,
+            mel_bands: 64,
+            mel_power: 2.0,
+            mel_log: Log::Db,
+            mfcc_coefficients: 13,
+            min_fundamental: 80.0,
+            max_fundamental: 400.0,
+            max_harmonics: 10,
+            harmonic_threshold: 6.0,
+            rolloff: 0.85,
+            energy_bands: 8,
+            deltas: 0,
+            delta_width: 2,
+            context_frames: 1,
+            grid_step: None,
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl FeatureConfig {
+    /// Reads settings from a JSON object or a TOML file of top-level `key = value` lines.
+    /// Settings that are not given keep their defaults.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the contents of a file `load` accepts.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut config = Self::default();
+        for (key, value) in pairs(text).map_err(invalid)? {
+            config.set(&key, &value).map_err(invalid)?;
+        }
+        config.validate().map_err(invalid)?;
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("invalid value {value} for {key}"))
+        }
+        match key {
+            "sample_rate" => self.sample_rate = number(key, value)?,
+            "frame_len" => self.frame_len = number(key, value)?,
+            "hop" => self.hop = number(key, value)?,
+            "min_freq" => self.min_freq = number(key, value)?,
+            "max_freq" => self.max_freq = number(key, value)?,
+            "smoothing_taps" => self.smoothing_taps = number(key, value)?,
+            "kaiser_beta" => self.kaiser_beta = number(key, value)?,
+            "fft_len" => self.fft_len = Some(number(key, value)?),
+            "grid_step" => self.grid_step = Some(number(key, value)?),
+            "mel_bands" => self.mel_bands = number(key, value)?,
+            "mel_power" => self.mel_power = number(key, value)?,
+            "mfcc_coefficients" => self.mfcc_coefficients = number(key, value)?,
+            "min_fundamental" => self.min_fundamental = number(key, value)?,
+            "max_fundamental" => self.max_fundamental = number(key, value)?,
+            "max_harmonics" => self.max_harmonics = number(key, value)?,
+            "harmonic_threshold" => self.harmonic_threshold = number(key, value)?,
+            "rolloff" => self.rolloff = number(key, value)?,
+            "energy_bands" => self.energy_bands = number(key, value)?,
+            "deltas" => self.deltas = number(key, value)?,
+            "delta_width" => self.delta_width = number(key, value)?,
+            "context_frames" => self.context_frames = number(key, value)?,
+            "kind" => {
+                self.kind = match value {
+                    "spectrum" => Kind::Spectrum,
+                    "log-mel" => Kind::LogMel,
+                    "mfcc" => Kind::Mfcc,
+                    "harmonic" => Kind::Harmonic,
+                    "summary" => Kind::Summary,
+                    _ => return Err(format!("unknown feature kind {value}")),
+                }
+            }
+            "mel_log" => {
+                self.mel_log = match value {
+                    "db" => Log::Db,
+                    "ln" => Log::Natural,
+                    "none" => Log::None,
+                    _ => return Err(format!("unknown mel_log {value}")),
+                }
+            }
+            "window" => {
+                self.window = match value {
+                    "hann" => Window::Hann,
+                    "hamming" => Window::Hamming,
+                    "blackman-harris" => Window::BlackmanHarris,
+                    "flat-top" => Window::FlatTop,
+                    "kaiser" => Window::Kaiser,
+                    _ => return Err(format!("unknown window {value}")),
+                }
+            }
+            "scaling" => {
+                self.scaling = match value {
+                    "min-max" => Scaling::MinMax,
+                    "none" => Scaling::None,
+                    _ => return Err(format!("unknown scaling {value}")),
+                }
+            }
+            _ => return Err(format!("unknown feature setting {key}")),
+        }
+        Ok(())
+    }
+
+    /// Checks that features can be computed with these settings.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sample_rate == 0 || self.frame_len == 0 || self.hop == 0 {
+            return Err("sample_rate, frame_len and hop must be positive".to_owned());
+        }
+        if self.min_freq.is_nan() || self.max_freq.is_nan() {
+            return Err("min_freq and max_freq must be numbers".to_owned());
+        }
+        let nyquist = self.sample_rate as f32 / 2.0;
+        if !(0.0 <= self.min_freq && self.min_freq < self.max_freq && self.max_freq <= nyquist) {
+            return Err(format!(
+                "frequency range {}-{} Hz is empty or beyond {nyquist} Hz",
+                self.min_freq, self.max_freq
+            ));
+        }
+        let fft_len = self.fft_len();
+        if !fft_len.is_power_of_two() || fft_len < self.frame_len.max(2) || fft_len > MAX_FFT_LEN {
+            return Err(format!(
+                "FFT length {fft_len} must be a power of two of at least 2 and frame_len, \
+                 up to {MAX_FFT_LEN}"
+            ));
+        }
+        // The spectrum keeps the bins from `min_freq` to `max_freq`, both included.
+        let bin = self.sample_rate as f64 / fft_len as f64;
+        let bins = (self.max_freq as f64 / bin).floor() - (self.min_freq as f64 / bin).ceil() + 1.0;
+        if bins < 2.0 {
+            return Err(format!(
+                "frequency range {}-{} Hz must span at least two FFT bins of {bin} Hz",
+                self.min_freq, self.max_freq
+            ));
+        }
+        if self.kaiser_beta.is_nan() || self.kaiser_beta < 0.0 {
+            return Err("kaiser_beta must not be negative".to_owned());
+        }
+        if self.smoothing_taps == 0 {
+            return Err("smoothing_taps must be positive".to_owned());
+        }
+        if self.mel_bands == 0 {
+            return Err("mel_bands must be positive".to_owned());
+        }
+        if self.mel_power.is_nan() || self.mel_power <= 0.0 {
+            return Err("mel_power must be positive".to_owned());
+        }
+        if self.kind == Kind::Mfcc {
+            if self.mfcc_coefficients == 0 || self.mfcc_coefficients > self.mel_bands {
+                return Err("mfcc_coefficients must be from 1 up to mel_bands".to_owned());
+            }
+            // The cepstrum separates the envelope from the fine structure only on a log scale.
+            if self.mel_log == Log::None {
+                return Err("MFCCs need mel_log db or ln".to_owned());
+            }
+        }
+        if self.kind == Kind::Harmonic {
+            if !(self.min_freq <= self.min_fundamental
+                && self.min_fundamental < self.max_fundamental
+                && self.max_fundamental <= self.max_freq)
+            {
+                return Err(format!(
+                    "fundamental range {}-{} Hz is empty or beyond the frequency range",
+                    self.min_fundamental, self.max_fundamental
+                ));
+            }
+            if self.max_harmonics == 0 || self.harmonic_threshold.is_nan() {
+                return Err(
+                    "max_harmonics must be positive and harmonic_threshold a number".to_owned(),
+                );
+            }
+        }
+        if !(0.0 < self.rolloff && self.rolloff <= 1.0) || self.energy_bands == 0 {
+            return Err("rolloff must be in (0, 1] and energy_bands positive".to_owned());
+        }
+        if self.deltas > 2 || self.delta_width == 0 {
+            return Err("deltas must be at most 2 and delta_width positive".to_owned());
+        }
+        if self.context_frames == 0 {
+            return Err("context_frames must be positive".to_owned());
+        }
+        if let Some(step) = self.grid_step
+            && !(step > 0.0 && (self.max_freq - self.min_freq) / step < MAX_GRID_LEN as f32)
+        {
+            return Err(format!(
+                "grid_step {step} must be positive and give at most {MAX_GRID_LEN} points"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Samples the FFT is computed over.
+    pub fn fft_len(&self) -> usize {
+        self.fft_len
+            .unwrap_or_else(|| self.frame_len.next_power_of_two())
+    }
+
+    /// Frequencies of the fixed grid, if one is set.
+    pub fn grid(&self) -> Option<Vec<f32>> {
+        let step = self.grid_step? as f64;
+        let (min, max) = (self.min_freq as f64, self.max_freq as f64);
+        // Tolerate rounding so a step dividing the range evenly ends on `max_freq`.
+        let len = ((max - min) / step + 1e-6).floor() as usize + 1;
+        Some((0..len).map(|i| (min + i as f64 * step) as f32).collect())
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut fields = vec![
+            ("kind", format!("\"{}\"", self.kind)),
+            ("sample_rate", self.sample_rate.to_string()),
+            ("frame_len", self.frame_len.to_string()),
+            ("hop", self.hop.to_string()),
+            ("min_freq", self.min_freq.to_string()),
+            ("max_freq", self.max_freq.to_string()),
+            ("window", format!("\"{}\"", self.window)),
+            ("kaiser_beta", self.kaiser_beta.to_string()),
+            ("fft_len", self.fft_len().to_string()),
+            ("smoothing_taps", self.smoothing_taps.to_string()),
+            ("scaling", format!("\"{}\"", self.scaling)),
+            ("mel_bands", self.mel_bands.to_string()),
+            ("mel_power", self.mel_power.to_string()),
+            ("mel_log", format!("\"{}\"", self.mel_log)),
+            ("mfcc_coefficients", self.mfcc_coefficients.to_string()),
+            ("min_fundamental", self.min_fundamental.to_string()),
+            ("max_fundamental", self.max_fundamental.to_string()),
+            ("max_harmonics", self.max_harmonics.to_string()),
+            ("harmonic_threshold", self.harmonic_threshold.to_string()),
+            ("rolloff", self.rolloff.to_string()),
+            ("energy_bands", self.energy_bands.to_string()),
+            ("deltas", self.deltas.to_string()),
+            ("delta_width", self.delta_width.to_string()),
+            ("context_frames", self.context_frames.to_string()),
+        ];
+        if let Some(step) = self.grid_step {
+            fields.push(("grid_step", step.to_string()));
+        }
+        let fields = fields
+            .iter()
+            .map(|(key, value)| format!("  \"{key}\": {value}"))
+            .collect::<Vec<_>>();
+        format!("{{\n{}\n}}\n", fields.join(",\n"))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_json())
+    }
+}
+
+/// Splits a flat JSON object or TOML document into keys and unquoted values.
+fn pairs(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut key = None;
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() || matches!(c, '{' | '}' | ',') => {
+                chars.next();
+            }
+            '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+            ':' | '=' if key.is_some() => {
+                chars.next();
+                while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+                let value = match chars.peek() {
+                    Some('"') => quoted(&mut chars)?,
+                    _ => {
+                        let mut value = String::new();
+                        while let Some(c) =
+                            chars.next_if(|c| !c.is_whitespace() && !matches!(c, ',' | '}' | '#'))
+                        {
+                            value.push(c);
+                        }
+                        value
+                    }
+                };
+                pairs.push((key.take().unwrap(), value));
+            }
+            '[' => return Err("tables are not supported in feature settings".to_owned()),
+            _ if key.is_none() => {
+                key = Some(if c == '"' {
+                    quoted(&mut chars)?
+                } else {
+                    let mut key = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                        key.push(c);
+                    }
+                    if key.is_empty() {
+                        return Err(format!("unexpected {c:?}"));
+                    }
+                    key
+                });
+            }
+            _ => return Err(format!("expected : or = after {}", key.unwrap())),
+        }
+    }
+    match key {
+        Some(key) => Err(format!("missing value for {key}")),
+        None => Ok(pairs),
+    }
+}
+
+/// Reads a double-quoted string without escapes, which no setting needs.
+fn quoted(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+    chars.next();
+    let mut s = String::new();
+    for c in chars.by_ref() {
+        if c == '"' {
+            return Ok(s);
+        }
+        s.push(c);
+    }
+    Err("unterminated string".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn reads_json_and_toml() {
+        let json = r#"{"kind": "mfcc", "hop": 512,"min_freq":20.5}"#;
+        let toml = "# features\nkind = \"mfcc\"\nhop = 512 # samples\n\nmin_freq=20.5\n";
+        let expected = [
+            pair("kind", "mfcc"),
+            pair("hop", "512"),
+            pair("min_freq", "20.5"),
+        ];
+        assert_eq!(pairs(json).unwrap(), expected);
+        assert_eq!(pairs(toml).unwrap(), expected);
+    }
+
+    #[test]
+    fn quoted_values_keep_separators() {
+        let text = r#"{"window": "a,b}#c", "hop": "1"}"#;
+        assert_eq!(
+            pairs(text).unwrap(),
+            [pair("window", "a,b}#c"), pair("hop", "1")]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_settings() {
+        for text in [
+            "[table]\nhop = 1",
+            "{\"hop\"}",
+            "kind = \"mfcc",
+            "hop 512",
+            "{\"hop\": 512, -}",
+        ] {
+            assert!(pairs(text).is_err(), "{text}");
+        }
+        for text in ["hop = -1", "kind = \"fft\"", "color = 1", "hop ="] {
+            assert!(FeatureConfig::parse(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let configs = [
+            FeatureConfig::default(),
+            FeatureConfig {
+                kind: Kind::Summary,
+                sample_rate: 16000,
+                frame_len: 1000,
+                hop: 250,
+                min_freq: 20.5,
+                max_freq: 7999.5,
+                window: Window::Kaiser,
+                kaiser_beta: 5.5,
+                fft_len: Some(4096),
+                scaling: Scaling::None,
+                mel_log: Log::Natural,
+                deltas: 2,
+                delta_width: 3,
+                context_frames: 4,
+                grid_step: Some(2.5),
+                ..FeatureConfig::default()
+            },
+        ];
+        for config in configs {
+            let parsed = FeatureConfig::parse(&config.to_json()).unwrap();
+            assert_eq!(
+                parsed,
+                FeatureConfig {
+                    fft_len: Some(config.fft_len()),
+                    ..config
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_settings_without_a_spectrum() {
+        let invalid = [
+            FeatureConfig {
+                frame_len: 1,
+                ..FeatureConfig::default()
+            },
+            FeatureConfig {
+                fft_len: Some(1),
+                frame_len: 1,
+                ..FeatureConfig::default()
+            },
+            // Bins are 48000 / 8192 = 5.9 Hz apart.
+            FeatureConfig {
+                min_freq: 100.0,
+                max_freq: 104.0,
+                ..FeatureConfig::default()
+            },
+            FeatureConfig {
+                min_freq: f32::NAN,
+                ..FeatureConfig::default()
+            },
+            FeatureConfig {
+                max_freq: f32::NAN,
+                ..FeatureConfig::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+        // The bins at 105.5 and 111.3 Hz.
+        let two_bins = FeatureConfig {
+            min_freq: 100.0,
+            max_freq: 112.0,
+            ..FeatureConfig::default()
+        };
+        assert!(two_bins.validate().is_ok());
+    }
+
+    #[test]
+    fn mfccs_need_log_energies() {
+        let linear = FeatureConfig {
+            kind: Kind::Mfcc,
+            mel_log: Log::None,
+            ..FeatureConfig::default()
+        };
+        assert!(linear.validate().is_err());
+        for mel_log in [Log::Db, Log::Natural] {
+            assert!(
+                FeatureConfig {
+                    mel_log,
+                    ..linear.clone()
+                }
+                .validate()
+                .is_ok()
+            );
+        }
+        let log_mel = FeatureConfig {
+            kind: Kind::LogMel,
+            ..linear
+        };
+        assert!(log_mel.validate().is_ok());
+    }
+}
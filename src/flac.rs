@@ -0,0 +1,1173 @@
+//! FLAC encoding for recordings and decoding of FLAC files as a source.
+//!
+//! The encoder uses the fixed predictors of orders 0 to 4 with Rice-coded residuals, plus
+//! wasted-bits detection and stereo decorrelation. That gets close to the reference encoder
+//! on microphone recordings without the cost of LPC analysis. The decoder handles everything
+//! the format allows, including LPC subframes written by other encoders.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use hound::{SampleFormat, WavSpec};
+
+use crate::{
+    bwf::{self, Metadata},
+    source::{AudioSource, Chunk, SourceError},
+    timestamp::Timestamp,
+};
+
+/// Frames per FLAC block.
+const BLOCK_SIZE: usize = 4096;
+
+/// Highest compression level accepted by `FlacWriter`.
+pub const MAX_LEVEL: u8 = 8;
+
+/// Widest samples `FlacWriter` stores. Many decoders reject 32-bit FLAC, so wider input
+/// keeps its top bits.
+const MAX_BITS: u16 = 24;
+
+const STREAMINFO_LEN: u32 = 34;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+const fn crc8_table() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC8: [u8; 256] = crc8_table();
+const CRC16: [u16; 256] = crc16_table();
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, b| CRC8[(crc ^ b) as usize])
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, b| {
+        (crc << 8) ^ CRC16[((crc >> 8) as u8 ^ b) as usize]
+    })
+}
+
+/// Collects a bit stream, most significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// Appends the low `n` bits of `value`, `n` being at most 32.
+    fn write(&mut self, value: u64, n: u32) {
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (value & ((1 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    /// Appends `value` as an `n`-bit two's complement number, `n` being at most 33.
+    fn write_signed(&mut self, value: i64, n: u32) {
+        if n > 32 {
+            self.write((value >> 32) as u64, n - 32);
+            self.write(value as u64, 32);
+        } else {
+            self.write(value as u64, n);
+        }
+    }
+
+    /// Appends `q` zeros followed by a one.
+    fn write_unary(&mut self, mut q: u64) {
+        while q >= 32 {
+            self.write(0, 32);
+            q -= 32;
+        }
+        self.write(1, q as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bytes.clear();
+        self.acc = 0;
+        self.bits = 0;
+    }
+}
+
+/// Writes `value` in the UTF-8-like coding FLAC uses for frame numbers.
+fn write_utf8(out: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        out.write(value, 8);
+        return;
+    }
+    let len = match value {
+        0x80..0x800 => 2,
+        0x800..0x1_0000 => 3,
+        0x1_0000..0x20_0000 => 4,
+        0x20_0000..0x400_0000 => 5,
+        0x400_0000..0x8000_0000 => 6,
+        _ => 7,
+    };
+    let prefix = (0xff00 >> len) as u64 & 0xff;
+    out.write(prefix | (value >> (6 * (len - 1))), 8);
+    for i in (0..len - 1).rev() {
+        out.write(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+/// Maps a residual to the unsigned value that is Rice coded.
+fn zigzag(residual: i64) -> u64 {
+    ((residual << 1) ^ (residual >> 63)) as u64
+}
+
+/// Computes the residual of the fixed predictor of `order` for `samples[order..]`.
+fn fixed_residual(samples: &[i64], order: usize, out: &mut Vec<i64>) {
+    out.clear();
+    let s = samples;
+    out.extend((order..s.len()).map(|i| match order {
+        0 => s[i],
+        1 => s[i] - s[i - 1],
+        2 => s[i] - 2 * s[i - 1] + s[i - 2],
+        3 => s[i] - 3 * s[i - 1] + 3 * s[i - 2] - s[i - 3],
+        _ => s[i] - 4 * s[i - 1] + 6 * s[i - 2] - 4 * s[i - 3] + s[i - 4],
+    }));
+}
+
+/// How much effort the encoder spends, derived from the compression level.
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    max_order: usize,
+    max_partition_order: u32,
+    stereo: bool,
+}
+
+impl Settings {
+    fn for_level(level: u8) -> Self {
+        Self {
+            max_order: if level == 0 { 2 } else { 4 },
+            max_partition_order: [3, 3, 3, 4, 4, 5, 6, 6, 8][level.min(MAX_LEVEL) as usize],
+            stereo: level > 0,
+        }
+    }
+}
+
+/// Rice partitioning chosen for a residual.
+#[derive(Debug, Clone, Default)]
+struct Rice {
+    partition_order: u32,
+    params: Vec<u32>,
+    bits: u64,
+}
+
+impl Rice {
+    /// Picks the partition order and parameters that minimise the estimated size of the
+    /// residual of a block of `block_len` samples whose first `order` have no residual.
+    fn plan(residual: &[i64], order: usize, block_len: usize, max_partition_order: u32) -> Self {
+        let mut max_order = max_partition_order.min(block_len.trailing_zeros());
+        while max_order > 0 && block_len >> max_order <= order {
+            max_order -= 1;
+        }
+        // Sums and counts of the finest partitions, merged pairwise for coarser ones.
+        let parts = 1 << max_order;
+        let part_len = block_len >> max_order;
+        let mut sums = vec![0u64; parts];
+        let mut counts = vec![0u64; parts];
+        for (i, r) in residual.iter().enumerate() {
+            let part = (i + order) / part_len;
+            sums[part] += zigzag(*r);
+            counts[part] += 1;
+        }
+
+        let mut best = Rice {
+            bits: u64::MAX,
+            ..Default::default()
+        };
+        for partition_order in (0..=max_order).rev() {
+            let mut params = Vec::with_capacity(sums.len());
+            let mut bits = 0;
+            for (sum, count) in sums.iter().zip(&counts) {
+                let (param, part_bits) = rice_param(*sum, *count);
+                params.push(param);
+                bits += part_bits;
+            }
+            let param_bits = if params.iter().any(|k| *k > 14) { 5 } else { 4 };
+            bits += 6 + param_bits * params.len() as u64;
+            if bits < best.bits {
+                best = Rice {
+                    partition_order,
+                    params,
+                    bits,
+                };
+            }
+            if partition_order > 0 {
+                sums = sums.chunks(2).map(|p| p[0] + p[1]).collect();
+                counts = counts.chunks(2).map(|p| p[0] + p[1]).collect();
+            }
+        }
+        best
+    }
+}
+
+/// Rice parameter for `count` values summing to `sum`, with the estimated coded size.
+fn rice_param(sum: u64, count: u64) -> (u32, u64) {
+    let cost = |k: u32| count * (k as u64 + 1) + (sum >> k);
+    if count == 0 {
+        return (0, 0);
+    }
+    let mean = sum / count;
+    let guess = if mean == 0 {
+        0
+    } else {
+        63 - mean.leading_zeros()
+    };
+    (guess.saturating_sub(1)..=(guess + 1).min(30))
+        .map(|k| (k, cost(k)))
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap()
+}
+
+#[derive(Debug, Clone)]
+enum SubframeKind {
+    Constant,
+    Verbatim,
+    Fixed { order: usize, rice: Rice },
+}
+
+/// Encoding chosen for one channel of a block.
+#[derive(Debug, Clone)]
+struct Subframe {
+    kind: SubframeKind,
+    /// Low bits that are zero in every sample and not stored.
+    wasted: u32,
+    bits: u64,
+}
+
+impl Subframe {
+    fn plan(samples: &[i64], bps: u32, settings: Settings, residual: &mut Vec<i64>) -> Self {
+        if samples.iter().all(|s| *s == samples[0]) {
+            return Self {
+                kind: SubframeKind::Constant,
+                wasted: 0,
+                bits: 8 + bps as u64,
+            };
+        }
+        let wasted = samples
+            .iter()
+            .fold(0, |acc, s| acc | s)
+            .trailing_zeros()
+            .min(bps - 1);
+        let bps = bps - wasted;
+        let shifted = samples.iter().map(|s| s >> wasted).collect::<Vec<_>>();
+        let header = 8 + wasted as u64;
+
+        let mut best = Self {
+            kind: SubframeKind::Verbatim,
+            wasted,
+            bits: header + samples.len() as u64 * bps as u64,
+        };
+        for order in 0..=settings.max_order.min(samples.len() - 1) {
+            fixed_residual(&shifted, order, residual);
+            if residual.iter().any(|r| i32::try_from(*r).is_err()) {
+                continue;
+            }
+            let rice = Rice::plan(residual, order, samples.len(), settings.max_partition_order);
+            let bits = header + order as u64 * bps as u64 + rice.bits;
+            if bits < best.bits {
+                best = Self {
+                    kind: SubframeKind::Fixed { order, rice },
+                    wasted,
+                    bits,
+                };
+            }
+        }
+        best
+    }
+
+    fn write(&self, out: &mut BitWriter, samples: &[i64], bps: u32, residual: &mut Vec<i64>) {
+        let kind = match self.kind {
+            SubframeKind::Constant => 0,
+            SubframeKind::Verbatim => 1,
+            SubframeKind::Fixed { order, .. } => 8 + order as u64,
+        };
+        out.write(kind << 1 | (self.wasted > 0) as u64, 8);
+        if self.wasted > 0 {
+            out.write_unary(self.wasted as u64 - 1);
+        }
+        let bps = bps - self.wasted;
+        let shifted = samples.iter().map(|s| s >> self.wasted);
+        match &self.kind {
+            SubframeKind::Constant => out.write_signed(samples[0], bps),
+            SubframeKind::Verbatim => shifted.for_each(|s| out.write_signed(s, bps)),
+            SubframeKind::Fixed { order, rice } => {
+                let shifted = shifted.collect::<Vec<_>>();
+                for s in &shifted[..*order] {
+                    out.write_signed(*s, bps);
+                }
+                fixed_residual(&shifted, *order, residual);
+                write_residual(out, residual, *order, samples.len(), rice);
+            }
+        }
+    }
+}
+
+fn write_residual(
+    out: &mut BitWriter,
+    residual: &[i64],
+    order: usize,
+    block_len: usize,
+    rice: &Rice,
+) {
+    let rice2 = rice.params.iter().any(|k| *k > 14);
+    out.write(rice2 as u64, 2);
+    out.write(rice.partition_order as u64, 4);
+    let part_len = block_len >> rice.partition_order;
+    let mut start = 0;
+    for (i, k) in rice.params.iter().enumerate() {
+        out.write(*k as u64, if rice2 { 5 } else { 4 });
+        let end = (i + 1) * part_len - order;
+        for r in &residual[start..end] {
+            let u = zigzag(*r);
+            out.write_unary(u >> k);
+            out.write(u, *k);
+        }
+        start = end;
+    }
+}
+
+/// Streams interleaved integer samples to a FLAC file.
+pub struct FlacWriter<W: Write + Seek> {
+    inner: W,
+    /// Layout of the stream as stored, with at most `MAX_BITS` bits per sample.
+    spec: WavSpec,
+    /// Low bits dropped from the samples passed in to fit `MAX_BITS`.
+    shift: u32,
+    settings: Settings,
+    /// Interleaved samples not yet making up a whole block.
+    pending: Vec<i32>,
+    channels: Vec<Vec<i64>>,
+    residual: Vec<i64>,
+    frame: BitWriter,
+    frame_index: u64,
+    /// Frames (in the WAV sense) encoded so far.
+    samples: u64,
+    min_frame_len: u32,
+    max_frame_len: u32,
+}
+
+impl FlacWriter<BufWriter<File>> {
+    /// Makes everything written so far survive a crash or power loss. Samples that do not
+    /// fill a block yet are kept in memory.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.write_streaminfo()?;
+        self.inner.flush()?;
+        self.inner.get_ref().sync_data()
+    }
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    /// `level` trades encoding speed for size, from 0 (fastest) to `MAX_LEVEL`. `metadata` is
+    /// stored as Vorbis comments. Samples wider than 24 bits are stored as 24-bit.
+    pub fn new(
+        mut inner: W,
+        spec: WavSpec,
+        metadata: Option<&Metadata>,
+        level: u8,
+    ) -> io::Result<Self> {
+        if spec.sample_format != SampleFormat::Int
+            || !(4..=32).contains(&spec.bits_per_sample)
+            || !(1..=8).contains(&spec.channels)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "FLAC needs 1 to 8 channels of 4 to 32-bit integer samples",
+            ));
+        }
+        let shift = spec.bits_per_sample.saturating_sub(MAX_BITS);
+        let spec = WavSpec {
+            bits_per_sample: spec.bits_per_sample - shift,
+            ..spec
+        };
+        inner.write_all(b"fLaC")?;
+        let comments = metadata.map(vorbis_comment);
+        let last = if comments.is_some() { 0 } else { 0x80 };
+        inner.write_all(&[last, 0, 0, STREAMINFO_LEN as u8])?;
+        inner.write_all(&[0; STREAMINFO_LEN as usize])?;
+        if let Some(comments) = comments {
+            let len = (comments.len() as u32).to_be_bytes();
+            inner.write_all(&[0x84, len[1], len[2], len[3]])?;
+            inner.write_all(&comments)?;
+        }
+        let mut writer = Self {
+            inner,
+            spec,
+            shift: shift as u32,
+            settings: Settings::for_level(level),
+            pending: Vec::new(),
+            channels: vec![Vec::with_capacity(BLOCK_SIZE); spec.channels as usize],
+            residual: Vec::with_capacity(BLOCK_SIZE),
+            frame: BitWriter::default(),
+            frame_index: 0,
+            samples: 0,
+            min_frame_len: 0,
+            max_frame_len: 0,
+        };
+        writer.write_streaminfo()?;
+        Ok(writer)
+    }
+
+    /// Writes integer samples using the full range of the spec's `bits_per_sample`.
+    pub fn write_int(&mut self, samples: &[i32]) -> io::Result<()> {
+        let block = BLOCK_SIZE * self.spec.channels as usize;
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend(samples.iter().map(|s| s >> self.shift));
+        let full = pending.len() / block * block;
+        for chunk in pending[..full].chunks_exact(block) {
+            self.write_frame(chunk)?;
+        }
+        pending.drain(..full);
+        self.pending = pending;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, samples: &[i32]) -> io::Result<()> {
+        let channels = self.spec.channels as usize;
+        let len = samples.len() / channels;
+        for (c, channel) in self.channels.iter_mut().enumerate() {
+            channel.clear();
+            channel.extend(samples[c..].iter().step_by(channels).map(|s| *s as i64));
+        }
+        let bps = self.spec.bits_per_sample as u32;
+
+        // Channel assignment and the (samples, bits per sample) of each subframe.
+        let residual = &mut self.residual;
+        let mut plan = |samples: &[i64], bps| Subframe::plan(samples, bps, self.settings, residual);
+        let (assignment, subframes) = if channels == 2 && self.settings.stereo {
+            let (left, right) = (&self.channels[0], &self.channels[1]);
+            let side = left
+                .iter()
+                .zip(right)
+                .map(|(l, r)| l - r)
+                .collect::<Vec<_>>();
+            let mid = left
+                .iter()
+                .zip(right)
+                .map(|(l, r)| (l + r) >> 1)
+                .collect::<Vec<_>>();
+            let l = plan(left, bps);
+            let r = plan(right, bps);
+            let s = plan(&side, bps + 1);
+            let m = plan(&mid, bps);
+            let modes = [
+                (1, l.bits + r.bits),
+                (8, l.bits + s.bits),
+                (9, s.bits + r.bits),
+                (10, m.bits + s.bits),
+            ];
+            let (assignment, _) = modes.into_iter().min_by_key(|(_, bits)| *bits).unwrap();
+            let subframes = match assignment {
+                1 => vec![(left.clone(), bps, l), (right.clone(), bps, r)],
+                8 => vec![(left.clone(), bps, l), (side, bps + 1, s)],
+                9 => vec![(side, bps + 1, s), (right.clone(), bps, r)],
+                _ => vec![(mid, bps, m), (side, bps + 1, s)],
+            };
+            (assignment, subframes)
+        } else {
+            let subframes = self
+                .channels
+                .iter()
+                .map(|channel| (channel.clone(), bps, plan(channel, bps)))
+                .collect();
+            (channels as u64 - 1, subframes)
+        };
+
+        let out = &mut self.frame;
+        out.clear();
+        out.write(0xfff8, 16);
+        let block_code = match len {
+            BLOCK_SIZE => 12,
+            ..=256 => 6,
+            _ => 7,
+        };
+        out.write(block_code, 4);
+        // Sample rate from STREAMINFO.
+        out.write(0, 4);
+        out.write(assignment, 4);
+        let size_code = match bps {
+            8 => 1,
+            12 => 2,
+            16 => 4,
+            20 => 5,
+            24 => 6,
+            _ => 0,
+        };
+        out.write(size_code, 3);
+        out.write(0, 1);
+        write_utf8(out, self.frame_index);
+        match block_code {
+            6 => out.write(len as u64 - 1, 8),
+            7 => out.write(len as u64 - 1, 16),
+            _ => {}
+        }
+        let crc = crc8(&out.bytes);
+        out.write(crc as u64, 8);
+
+        for (samples, bps, subframe) in &subframes {
+            subframe.write(out, samples, *bps, &mut self.residual);
+        }
+        out.align();
+        let crc = crc16(&out.bytes);
+        out.write(crc as u64, 16);
+
+        self.inner.write_all(&out.bytes)?;
+        let frame_len = out.bytes.len() as u32;
+        self.min_frame_len = match self.min_frame_len {
+            0 => frame_len,
+            min => min.min(frame_len),
+        };
+        self.max_frame_len = self.max_frame_len.max(frame_len);
+        self.frame_index += 1;
+        self.samples += len as u64;
+        Ok(())
+    }
+
+    /// Rewrites STREAMINFO with the stream length and frame sizes so far.
+    fn write_streaminfo(&mut self) -> io::Result<()> {
+        let end = self.inner.stream_position()?;
+        let mut info = BitWriter::default();
+        info.write(BLOCK_SIZE as u64, 16);
+        info.write(BLOCK_SIZE as u64, 16);
+        info.write(self.min_frame_len as u64, 24);
+        info.write(self.max_frame_len as u64, 24);
+        info.write(self.spec.sample_rate as u64, 20);
+        info.write(self.spec.channels as u64 - 1, 3);
+        info.write(self.spec.bits_per_sample as u64 - 1, 5);
+        info.write(self.samples >> 32, 4);
+        info.write(self.samples, 32);
+        // No MD5 signature.
+        info.bytes.resize(STREAMINFO_LEN as usize, 0);
+        self.inner.seek(SeekFrom::Start(8))?;
+        self.inner.write_all(&info.bytes)?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> io::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        if !pending.is_empty() {
+            self.write_frame(&pending)?;
+        }
+        self.write_streaminfo()?;
+        self.inner.flush()
+    }
+}
+
+/// Body of a VORBIS_COMMENT block describing `meta`.
+fn vorbis_comment(meta: &Metadata) -> Vec<u8> {
+    let mut comments = vec![
+        format!("ORIGINATOR={}", meta.originator),
+        format!("DEVICE={}", meta.device),
+        format!("NODE_ID={}", meta.node_id),
+        format!("CHANNEL_NAMES={}", meta.channel_names.join(",")),
+        format!("TIME_REFERENCE={}", meta.time_reference),
+    ];
+    if let Some(start) = &meta.origination {
+        comments.push(format!("DATE={}T{}Z", start.iso_date(), start.iso_time()));
+    }
+    if let Some(gain) = meta.gain_db {
+        comments.push(format!("GAIN_DB={gain}"));
+    }
+    if let Some((lat, lon)) = meta.gps {
+        comments.push(format!("GPS={lat}, {lon}"));
+    }
+
+    let vendor = env!("CARGO_PKG_NAME");
+    let mut block = Vec::new();
+    block.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    block.extend_from_slice(vendor.as_bytes());
+    block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        block.extend_from_slice(comment.as_bytes());
+    }
+    block
+}
+
+/// Reads the comments written by `vorbis_comment` into `meta`, ignoring anything else.
+fn parse_vorbis_comment(block: &[u8], meta: &mut Metadata) {
+    let read_u32 = |at: usize| -> Option<usize> {
+        Some(u32::from_le_bytes(block.get(at..at + 4)?.try_into().unwrap()) as usize)
+    };
+    let Some(vendor_len) = read_u32(0) else {
+        return;
+    };
+    let mut pos = 4 + vendor_len;
+    let Some(count) = read_u32(pos) else {
+        return;
+    };
+    pos += 4;
+    for _ in 0..count {
+        let Some(len) = read_u32(pos) else {
+            return;
+        };
+        let Some(comment) = block.get(pos + 4..pos + 4 + len) else {
+            return;
+        };
+        pos += 4 + len;
+        let comment = String::from_utf8_lossy(comment);
+        let Some((key, value)) = comment.split_once('=') else {
+            continue;
+        };
+        let value = value.to_owned();
+        match key.to_ascii_uppercase().as_str() {
+            "ORIGINATOR" => meta.originator = value,
+            "DEVICE" => meta.device = value,
+            "NODE_ID" => meta.node_id = value,
+            "CHANNEL_NAMES" if !value.is_empty() => {
+                meta.channel_names = value.split(',').map(str::to_owned).collect()
+            }
+            "TIME_REFERENCE" => meta.time_reference = value.parse().unwrap_or(0),
+            "DATE" => {
+                meta.origination = value
+                    .split_once('T')
+                    .and_then(|(date, time)| Timestamp::parse(date, time.trim_end_matches('Z')))
+            }
+            "GAIN_DB" => meta.gain_db = value.parse().ok(),
+            "GPS" => meta.gps = bwf::parse_gps(&value),
+            _ => {}
+        }
+    }
+}
+
+/// Reads a bit stream, most significant bit first.
+struct BitReader<R> {
+    inner: R,
+    acc: u64,
+    bits: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    fn fill_byte(&mut self) -> io::Result<()> {
+        let mut byte = [0];
+        self.inner.read_exact(&mut byte)?;
+        self.acc = (self.acc << 8) | byte[0] as u64;
+        self.bits += 8;
+        Ok(())
+    }
+
+    /// Reads `n` bits, at most 32, as an unsigned number.
+    fn read(&mut self, n: u32) -> io::Result<u64> {
+        while self.bits < n {
+            self.fill_byte()?;
+        }
+        self.bits -= n;
+        Ok((self.acc >> self.bits) & ((1 << n) - 1))
+    }
+
+    /// Reads an `n`-bit two's complement number, `n` being at most 33.
+    fn read_signed(&mut self, n: u32) -> io::Result<i64> {
+        if n == 0 {
+            return Ok(0);
+        }
+        let value = if n > 32 {
+            (self.read(n - 32)? << 32) | self.read(32)?
+        } else {
+            self.read(n)?
+        };
+        Ok(((value << (64 - n)) as i64) >> (64 - n))
+    }
+
+    /// Counts zeros up to and including the next one.
+    fn read_unary(&mut self) -> io::Result<u64> {
+        let mut zeros = 0;
+        loop {
+            if self.bits == 0 {
+                self.fill_byte()?;
+            }
+            let available = self.acc & ((1 << self.bits) - 1);
+            if available == 0 {
+                zeros += self.bits as u64;
+                self.bits = 0;
+                continue;
+            }
+            let one = 63 - available.leading_zeros();
+            zeros += (self.bits - 1 - one) as u64;
+            self.bits = one;
+            return Ok(zeros);
+        }
+    }
+
+    /// Skips to the next byte boundary.
+    fn align(&mut self) {
+        self.bits -= self.bits % 8;
+    }
+
+    fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        (0..len).map(|_| self.read(8).map(|b| b as u8)).collect()
+    }
+}
+
+fn read_utf8<R: Read>(reader: &mut BitReader<R>) -> io::Result<u64> {
+    let first = reader.read(8)?;
+    let len = (first as u8).leading_ones();
+    if len == 0 {
+        return Ok(first);
+    }
+    if len == 1 || len > 7 {
+        return Err(invalid("invalid FLAC frame number"));
+    }
+    let mut value = first & (0x7f >> len);
+    for _ in 1..len {
+        value = (value << 6) | (reader.read(8)? & 0x3f);
+    }
+    Ok(value)
+}
+
+/// A FLAC file.
+pub struct FlacSource {
+    reader: BitReader<BufReader<File>>,
+    spec: WavSpec,
+    metadata: Metadata,
+    channels: Vec<Vec<i64>>,
+    buf: Vec<i32>,
+}
+
+impl FlacSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SourceError> {
+        let mut reader = BitReader::new(BufReader::new(File::open(path)?));
+        let mut magic = reader.read_bytes(4)?;
+        if &magic[..3] == b"ID3" {
+            // Skip an ID3v2 tag, whose size is stored in 7-bit bytes.
+            let header = reader.read_bytes(6)?;
+            let len = header[2..]
+                .iter()
+                .fold(0, |len, b| (len << 7) | (b & 0x7f) as usize);
+            let footer = if header[1] & 0x10 != 0 { 10 } else { 0 };
+            reader.read_bytes(len + footer)?;
+            magic = reader.read_bytes(4)?;
+        }
+        if magic != b"fLaC" {
+            return Err(invalid("not a FLAC file").into());
+        }
+
+        let mut spec = None;
+        let mut metadata = Metadata::default();
+        loop {
+            let last = reader.read(1)? == 1;
+            let kind = reader.read(7)?;
+            let len = reader.read(24)? as usize;
+            let block = reader.read_bytes(len)?;
+            match kind {
+                0 if len >= 18 => {
+                    let mut info = BitReader::new(&block[10..18]);
+                    let sample_rate = info.read(20)? as u32;
+                    let channels = info.read(3)? as u16 + 1;
+                    let bits_per_sample = info.read(5)? as u16 + 1;
+                    spec = Some(WavSpec {
+                        channels,
+                        sample_rate,
+                        bits_per_sample,
+                        sample_format: SampleFormat::Int,
+                    });
+                }
+                4 => parse_vorbis_comment(&block, &mut metadata),
+                _ => {}
+            }
+            if last {
+                break;
+            }
+        }
+        let spec = spec.ok_or_else(|| invalid("FLAC file without STREAMINFO"))?;
+        Ok(Self {
+            reader,
+            spec,
+            metadata,
+            channels: vec![Vec::new(); spec.channels as usize],
+            buf: Vec::new(),
+        })
+    }
+
+    /// Decodes the next frame into `channels`, returning its length in samples per channel.
+    fn read_frame(&mut self) -> io::Result<usize> {
+        let r = &mut self.reader;
+        let sync = r.read(16)?;
+        if sync & 0xfffe != 0xfff8 {
+            return Err(invalid("lost FLAC frame sync"));
+        }
+        let block_code = r.read(4)?;
+        let rate_code = r.read(4)?;
+        let assignment = r.read(4)?;
+        let size_code = r.read(3)?;
+        r.read(1)?;
+        read_utf8(r)?;
+        let len = match block_code {
+            1 => 192,
+            2..=5 => 576 << (block_code - 2),
+            6 => r.read(8)? as usize + 1,
+            7 => r.read(16)? as usize + 1,
+            8..=15 => 256 << (block_code - 8),
+            _ => return Err(invalid("reserved FLAC block size")),
+        };
+        match rate_code {
+            12 => {
+                r.read(8)?;
+            }
+            13 | 14 => {
+                r.read(16)?;
+            }
+            15 => return Err(invalid("invalid FLAC sample rate")),
+            _ => {}
+        }
+        let bps = match size_code {
+            0 => self.spec.bits_per_sample as u32,
+            1 => 8,
+            2 => 12,
+            4 => 16,
+            5 => 20,
+            6 => 24,
+            7 => 32,
+            _ => return Err(invalid("reserved FLAC sample size")),
+        };
+        // Header CRC.
+        r.read(8)?;
+
+        let channels = self.spec.channels as usize;
+        let expected = match assignment {
+            0..=7 => assignment as usize + 1,
+            8..=10 => 2,
+            _ => return Err(invalid("reserved FLAC channel assignment")),
+        };
+        if expected != channels {
+            return Err(invalid("FLAC frame channel count differs from STREAMINFO"));
+        }
+        for (c, channel) in self.channels.iter_mut().enumerate() {
+            let side = matches!((assignment, c), (8, 1) | (9, 0) | (10, 1));
+            read_subframe(r, channel, len, bps + side as u32)?;
+        }
+        r.align();
+        // Frame CRC.
+        r.read(16)?;
+
+        if let [a, b] = &mut self.channels[..] {
+            for (a, b) in a.iter_mut().zip(b.iter_mut()) {
+                (*a, *b) = match assignment {
+                    8 => (*a, *a - *b),
+                    9 => (*a + *b, *b),
+                    10 => {
+                        let mid = (*a << 1) | (*b & 1);
+                        ((mid + *b) >> 1, (mid - *b) >> 1)
+                    }
+                    _ => (*a, *b),
+                };
+            }
+        }
+        Ok(len)
+    }
+}
+
+fn read_subframe<R: Read>(
+    r: &mut BitReader<R>,
+    out: &mut Vec<i64>,
+    len: usize,
+    bps: u32,
+) -> io::Result<()> {
+    if r.read(1)? != 0 {
+        return Err(invalid("invalid FLAC subframe header"));
+    }
+    let kind = r.read(6)?;
+    let wasted = if r.read(1)? == 1 {
+        r.read_unary()? as u32 + 1
+    } else {
+        0
+    };
+    if wasted >= bps {
+        return Err(invalid("invalid FLAC wasted bits"));
+    }
+    let bps = bps - wasted;
+    out.clear();
+    match kind {
+        0 => {
+            let value = r.read_signed(bps)?;
+            out.resize(len, value);
+        }
+        1 => {
+            for _ in 0..len {
+                out.push(r.read_signed(bps)?);
+            }
+        }
+        8..=12 => {
+            let order = kind as usize - 8;
+            for _ in 0..order {
+                out.push(r.read_signed(bps)?);
+            }
+            read_residual(r, out, order, len)?;
+            for i in order..len {
+                let s = &out[..i];
+                let prediction = match order {
+                    0 => 0,
+                    1 => s[i - 1],
+                    2 => 2 * s[i - 1] - s[i - 2],
+                    3 => 3 * s[i - 1] - 3 * s[i - 2] + s[i - 3],
+                    _ => 4 * s[i - 1] - 6 * s[i - 2] + 4 * s[i - 3] - s[i - 4],
+                };
+                out[i] += prediction;
+            }
+        }
+        32..=63 => {
+            let order = kind as usize - 31;
+            for _ in 0..order {
+                out.push(r.read_signed(bps)?);
+            }
+            let precision = r.read(4)? as u32 + 1;
+            if precision == 16 {
+                return Err(invalid("invalid FLAC LPC precision"));
+            }
+            let shift = r.read_signed(5)?;
+            if shift < 0 {
+                return Err(invalid("negative FLAC LPC shift"));
+            }
+            let coefs = (0..order)
+                .map(|_| r.read_signed(precision))
+                .collect::<io::Result<Vec<_>>>()?;
+            read_residual(r, out, order, len)?;
+            for i in order..len {
+                let prediction = coefs
+                    .iter()
+                    .enumerate()
+                    .map(|(j, c)| c * out[i - 1 - j])
+                    .sum::<i64>();
+                out[i] += prediction >> shift;
+            }
+        }
+        _ => return Err(invalid("reserved FLAC subframe type")),
+    }
+    if wasted > 0 {
+        out.iter_mut().for_each(|s| *s <<= wasted);
+    }
+    Ok(())
+}
+
+/// Appends the residual of a block of `len` samples whose first `order` are warm-up samples.
+fn read_residual<R: Read>(
+    r: &mut BitReader<R>,
+    out: &mut Vec<i64>,
+    order: usize,
+    len: usize,
+) -> io::Result<()> {
+    let param_bits = match r.read(2)? {
+        0 => 4,
+        1 => 5,
+        _ => return Err(invalid("reserved FLAC residual coding")),
+    };
+    let escape = (1 << param_bits) - 1;
+    let partition_order = r.read(4)?;
+    let parts = 1 << partition_order;
+    if !len.is_multiple_of(parts) || len / parts < order {
+        return Err(invalid("invalid FLAC partition order"));
+    }
+    for part in 0..parts {
+        let count = len / parts - if part == 0 { order } else { 0 };
+        let param = r.read(param_bits)?;
+        if param == escape {
+            let bits = r.read(5)? as u32;
+            for _ in 0..count {
+                out.push(r.read_signed(bits)?);
+            }
+        } else {
+            let k = param as u32;
+            for _ in 0..count {
+                let u = (r.read_unary()? << k) | r.read(k)?;
+                out.push((u >> 1) as i64 ^ -((u & 1) as i64));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl AudioSource for FlacSource {
+    fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        (!self.metadata.is_empty()).then_some(&self.metadata)
+    }
+
+    fn read(&mut self) -> Result<Option<Chunk<'_>>, SourceError> {
+        let len = match self.read_frame() {
+            Ok(len) => len,
+            // The end of the file, or of a recording that was cut off mid-frame.
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        self.buf.clear();
+        for i in 0..len {
+            self.buf.extend(self.channels.iter().map(|c| c[i] as i32));
+        }
+        Ok(Some(Chunk::Int(&self.buf)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    fn spec(channels: u16, bits_per_sample: u16) -> WavSpec {
+        WavSpec {
+            channels,
+            sample_rate: 48000,
+            bits_per_sample,
+            sample_format: SampleFormat::Int,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("flac-{}-{name}.flac", std::process::id()))
+    }
+
+    /// `frames` frames of a tone plus noise, with full-scale samples mixed in so the side
+    /// channel needs its extra bit.
+    fn signal(spec: WavSpec, frames: usize) -> Vec<i32> {
+        let max = (1i64 << (spec.bits_per_sample - 1)) - 1;
+        let mut state = 1u32;
+        (0..frames * spec.channels as usize)
+            .map(|i| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let noise = (state >> 16) as f64 / 65536.0 - 0.5;
+                let tone = (i as f64 * 0.01).sin();
+                match i % 997 {
+                    0 => max as i32,
+                    1 => -max as i32 - 1,
+                    _ => ((0.7 * tone + 0.2 * noise) * max as f64) as i32,
+                }
+            })
+            .collect()
+    }
+
+    /// Encodes `samples`, passed in pieces of 1000 frames, and decodes them again.
+    fn round_trip(name: &str, spec: WavSpec, samples: &[i32], level: u8) -> (WavSpec, Vec<i32>) {
+        let path = temp_path(name);
+        let file = BufWriter::new(File::create(&path).unwrap());
+        let mut writer = FlacWriter::new(file, spec, None, level).unwrap();
+        for piece in samples.chunks(1000 * spec.channels as usize) {
+            writer.write_int(piece).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut source = FlacSource::open(&path).unwrap();
+        let mut decoded = Vec::new();
+        while let Some(chunk) = source.read().unwrap() {
+            match chunk {
+                Chunk::Int(samples) => decoded.extend_from_slice(samples),
+                Chunk::Float(_) => panic!("FLAC decoded to floats"),
+            }
+        }
+        fs::remove_file(path).unwrap();
+        (source.spec(), decoded)
+    }
+
+    #[test]
+    fn round_trips_16_and_24_bit() {
+        for bits in [16, 24] {
+            for channels in [1, 2, 3] {
+                for level in [0, 5, MAX_LEVEL] {
+                    let spec = spec(channels, bits);
+                    let samples = signal(spec, 3 * BLOCK_SIZE);
+                    let name = format!("{bits}-{channels}-{level}");
+                    let (decoded_spec, decoded) = round_trip(&name, spec, &samples, level);
+                    assert_eq!(decoded_spec, spec);
+                    assert!(
+                        decoded == samples,
+                        "{bits}-bit, {channels} ch, level {level}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_partial_final_blocks() {
+        // Short blocks have their size coded in 8 bits, longer ones in 16.
+        for frames in [1, 2, 255, 256, 257, BLOCK_SIZE - 1, BLOCK_SIZE + 1] {
+            let spec = spec(2, 16);
+            let samples = signal(spec, frames);
+            let (_, decoded) = round_trip(&format!("partial-{frames}"), spec, &samples, 5);
+            assert!(decoded == samples, "{frames} frames");
+        }
+    }
+
+    #[test]
+    fn round_trips_silence_and_constant_blocks() {
+        let spec = spec(2, 24);
+        let mut samples = vec![0; 2 * BLOCK_SIZE];
+        samples.extend(std::iter::repeat_n(-12345, 2 * BLOCK_SIZE));
+        let (_, decoded) = round_trip("constant", spec, &samples, 5);
+        assert!(decoded == samples);
+    }
+
+    #[test]
+    fn stores_32_bit_samples_as_24_bit() {
+        let samples = signal(spec(2, 32), BLOCK_SIZE + 100);
+        let (decoded_spec, decoded) = round_trip("32-bit", spec(2, 32), &samples, 5);
+        assert_eq!(decoded_spec, spec(2, 24));
+        let expected = samples.iter().map(|s| s >> 8).collect::<Vec<_>>();
+        assert!(decoded == expected);
+    }
+}
@@ -0,0 +1,154 @@
+//! Harmonic analysis. Rotors produce a tone at the blade-passing frequency and a series of
+//! harmonics at its multiples, which a comb swept over the spectrum picks out: its teeth
+//! gain at every harmonic of a candidate fundamental and lose halfway between them, so half
+//! and double the true fundamental score lower than the fundamental itself.
+
+use crate::features::FeatureConfig;
+
+/// Smallest magnitude, keeping the logarithms of silent bins finite.
+const TINY: f32 = 1e-20;
+/// Candidate fundamentals per FFT bin.
+const STEPS_PER_BIN: f32 = 4.0;
+
+/// Harmonic series found in a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Harmonics {
+    /// Fundamental frequency in Hz.
+    pub fundamental: f32,
+    /// Harmonics, counting the fundamental, at least `harmonic_threshold` above the
+    /// background.
+    pub count: usize,
+    /// Energy at the harmonics over the energy between them, in dB.
+    pub hnr: f32,
+}
+
+impl Harmonics {
+    /// The values as features.
+    pub fn to_vec(self) -> Vec<f32> {
+        vec![self.fundamental, self.count as f32, self.hnr]
+    }
+}
+
+/// Mean of the `taps` values around each one, over fewer values near the ends.
+fn moving_average(values: &[f32], taps: usize) -> Vec<f32> {
+    let half = taps / 2;
+    (0..values.len())
+        .map(|i| {
+            let around = &values[i.saturating_sub(half)..(i + half + 1).min(values.len())];
+            around.iter().sum::<f32>() / around.len() as f32
+        })
+        .collect()
+}
+
+/// Finds the strongest harmonic series in FFT `magnitudes` at evenly spaced `freqs`, with
+/// the fundamental between `min_fundamental` and `max_fundamental` of `config`.
+pub fn analyze(freqs: &[f32], magnitudes: &[f32], config: &FeatureConfig) -> Harmonics {
+    let bin = freqs[1] - freqs[0];
+    let background = moving_average(magnitudes, config.smoothing_taps);
+    // Level of each bin above its background in dB, positive at tonal peaks.
+    let levels = magnitudes
+        .iter()
+        .zip(&background)
+        .map(|(m, b)| 20.0 * (m.max(TINY) / b.max(TINY)).log10())
+        .collect::<Vec<_>>();
+    // Bins within one of `f`, none beyond the spectrum.
+    let near = |f: f32| {
+        let i = ((f - freqs[0]) / bin).round() as isize;
+        let len = freqs.len() as isize;
+        (i - 1).clamp(0, len) as usize..(i + 2).clamp(0, len) as usize
+    };
+    let level = |f: f32| levels[near(f)].iter().copied().fold(0.0f32, f32::max);
+    let harmonics = |fundamental: f32| {
+        (1..=config.max_harmonics)
+            .map(move |h| h as f32 * fundamental)
+            .take_while(|f| *f <= freqs[freqs.len() - 1])
+    };
+
+    let step = bin / STEPS_PER_BIN;
+    let candidates = ((config.max_fundamental - config.min_fundamental) / step) as usize + 1;
+    let score = |fundamental: f32| {
+        harmonics(fundamental)
+            .map(|f| level(f) - level(f - fundamental / 2.0))
+            .sum::<f32>()
+    };
+    let fundamental = (0..candidates)
+        .map(|i| config.min_fundamental + i as f32 * step)
+        .max_by(|a, b| score(*a).total_cmp(&score(*b)))
+        .unwrap();
+
+    let count = harmonics(fundamental)
+        .filter(|f| level(*f) >= config.harmonic_threshold)
+        .count();
+    let mut at_harmonics = vec![false; freqs.len()];
+    for f in harmonics(fundamental) {
+        at_harmonics[near(f)].fill(true);
+    }
+    let last = harmonics(fundamental).last().unwrap_or(fundamental);
+    let span = near(fundamental / 2.0).start..near(last + fundamental / 2.0).end;
+    let (mut harmonic_energy, mut other_energy) = (0.0, 0.0);
+    for i in span {
+        let energy = magnitudes[i] * magnitudes[i];
+        if at_harmonics[i] {
+            harmonic_energy += energy;
+        } else {
+            other_energy += energy;
+        }
+    }
+    Harmonics {
+        fundamental,
+        count,
+        hnr: 10.0 * (harmonic_energy.max(TINY) / other_energy.max(TINY)).log10(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Low, uneven background of `len` bins.
+    fn background(len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| 0.01 * (1.5 + (i as f32 * 1.7).sin()))
+            .collect()
+    }
+
+    /// Spectrum of a rotor with fundamental `fundamental`: harmonics falling off as `1/h` on
+    /// a low, uneven background, at the bins of a 8192-point FFT at 48 kHz.
+    fn rotor(fundamental: f32) -> (Vec<f32>, Vec<f32>) {
+        let bin = 48000.0 / 8192.0;
+        let freqs = (1..=(4000.0 / bin) as usize)
+            .map(|i| i as f32 * bin)
+            .collect::<Vec<_>>();
+        let mut magnitudes = background(freqs.len());
+        for h in 1..=10 {
+            let i = (h as f32 * fundamental / bin).round() as usize - 1;
+            magnitudes[i] = 1.0 / h as f32;
+        }
+        (freqs, magnitudes)
+    }
+
+    #[test]
+    fn finds_the_fundamental_rather_than_half_or_double() {
+        let config = FeatureConfig::default();
+        for fundamental in [120.0, 200.0, 310.0] {
+            let (freqs, magnitudes) = rotor(fundamental);
+            let bin = freqs[1] - freqs[0];
+            let found = analyze(&freqs, &magnitudes, &config);
+            assert!(
+                (found.fundamental - fundamental).abs() < bin,
+                "{fundamental} Hz found as {}",
+                found.fundamental
+            );
+            assert_eq!(found.count, config.max_harmonics);
+            assert!(found.hnr > 10.0, "HNR {}", found.hnr);
+        }
+    }
+
+    #[test]
+    fn noise_has_no_harmonics() {
+        let config = FeatureConfig::default();
+        let (freqs, _) = rotor(200.0);
+        let found = analyze(&freqs, &background(freqs.len()), &config);
+        assert!(found.count < 4, "{found:?}");
+    }
+}
@@ -1,24 +1,50 @@
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufWriter, Write},
-    sync::atomic::{AtomicBool, Ordering},
-    thread,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, TrySendError},
+    },
+    thread::{self, Scope},
+    time::Instant,
 };
 
 use alsa::pcm::Format;
 use audio::CaptureDevice;
+use bwf::Metadata;
 use circular_buffer::CircularBuffer;
-use clap::{Parser, Subcommand, command};
-use ndarray::Array2;
-use ort::{
-    inputs,
-    value::{DynMapValueType, Sequence, TensorRef},
+use clap::{Parser, Subcommand};
+use clips::ClipRecorder;
+use features::FeatureConfig;
+use hound::WavSpec;
+use ort::session::Session;
+use recorder::{CaptureStats, Encoding, Output};
+use resample::Resampler;
+use signal_hook::{
+    consts::SIGINT,
+    iterator::{Handle, Signals},
 };
-use signal_hook::{consts::SIGINT, iterator::Signals};
+use source::{AudioSource, ChannelMode, Framer, RawSource, SourceError, SyntheticSource};
 
 mod audio;
+mod bwf;
+mod clips;
+mod features;
+mod flac;
+mod harmonic;
+mod mel;
+mod mfcc;
 mod models;
+mod recorder;
+mod resample;
+mod ring;
+mod source;
+mod summary;
+mod timestamp;
+mod wav;
+
+const DEVICE_NAME: &str = "hw:CARD=sndrpigooglevoi,DEV=0";
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -33,137 +59,776 @@ enum Commands {
     Record(RecordArgs),
     GenCsv(GenCsvArgs),
     Test(TestArgs),
+    Detect(DetectArgs),
+    /// Detect drones by the harmonic series of their rotors, without a model
+    Harmonics(HarmonicsArgs),
+    /// List ALSA cards and capture PCMs with their supported parameters
+    ListDevices,
+    /// Fix the header of WAV recordings that were cut off before being finalized
+    Repair(RepairArgs),
+}
+
+/// Options describing the capture device and raw stdin or synthetic inputs.
+#[derive(clap::Args)]
+struct SourceArgs {
+    /// ALSA PCM to capture from when the input is `alsa`
+    #[arg(long, short = 'D', default_value = DEVICE_NAME)]
+    device: String,
+    /// Sample rate requested from the device or of raw/synthetic input
+    #[arg(long, default_value_t = 48000)]
+    rate: u32,
+    /// Channel count requested from the device or of raw/synthetic input
+    #[arg(long, default_value_t = 2)]
+    channels: u16,
+    /// Sample format requested from the device or of raw/synthetic input
+    /// (S16_LE, S16_BE, S24_3LE, S24_LE, S32_LE, S32_BE, FLOAT_LE or FLOAT_BE)
+    #[arg(long, default_value = "S32_LE")]
+    format: Format,
+    /// Keep capturing when the device cannot run at --rate, at the rate it picked instead
+    #[arg(long)]
+    allow_rate_mismatch: bool,
+    /// Frequency of the synthetic tone in Hz
+    #[arg(long, default_value_t = 440.0)]
+    tone_hz: f32,
+    /// Amplitude of the synthetic tone relative to full scale
+    #[arg(long, default_value_t = 0.5)]
+    tone_level: f32,
+    /// Amplitude of the synthetic white noise relative to full scale
+    #[arg(long, default_value_t = 0.0)]
+    noise_level: f32,
+    /// Length of the synthetic signal in seconds, endless if omitted
+    #[arg(long)]
+    duration: Option<f32>,
+}
+
+/// Options deciding how features are computed from the input.
+#[derive(clap::Args)]
+struct FeatureArgs {
+    /// Channel features are computed from: its number counting from 1, `mix` for the average
+    /// of all channels or `each` for every channel separately
+    #[arg(long, default_value = "mix", value_parser = parse_channel_mode)]
+    channel: ChannelMode,
+    /// JSON or TOML file with the feature settings; for models, their metadata is used if
+    /// omitted
+    #[arg(long)]
+    feature_config: Option<String>,
+    /// Samples per channel in each frame, overriding the feature settings
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    frame_len: Option<u32>,
+    /// Samples per channel from the start of one frame to the next, overriding the feature
+    /// settings
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    hop: Option<u32>,
+}
+
+impl FeatureArgs {
+    /// Feature settings from --feature-config, else from the metadata of `model`, else the
+    /// defaults, with --frame-len and --hop applied on top.
+    fn config(&self, model: Option<&Session>) -> Result<FeatureConfig, String> {
+        let mut config = match (&self.feature_config, model) {
+            (Some(path), _) => FeatureConfig::load(path).map_err(|err| format!("{path}: {err}"))?,
+            (None, Some(model)) => models::feature_config(model)?.unwrap_or_else(|| {
+                println!("Model has no feature settings, using the defaults");
+                FeatureConfig::default()
+            }),
+            (None, None) => FeatureConfig::default(),
+        };
+        if let Some(len) = self.frame_len {
+            // Frames that did not overlap keep not overlapping.
+            if config.hop == config.frame_len {
+                config.hop = len as usize;
+            }
+            config.frame_len = len as usize;
+        }
+        if let Some(hop) = self.hop {
+            config.hop = hop as usize;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Framer cutting frames of `config` holding all channels of `spec` interleaved.
+fn framer(config: &FeatureConfig, spec: WavSpec) -> Framer {
+    let channels = spec.channels as usize;
+    Framer::new(config.frame_len * channels, config.hop * channels, spec)
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Codec {
+    Wav,
+    Flac,
 }
 
 #[derive(clap::Args)]
 struct RecordArgs {
-    #[arg(long, short = 'o')]
-    output_file: String,
+    /// Write a single file here instead of timestamped files in --output-dir
+    #[arg(long, short = 'o', conflicts_with_all = ["segment_minutes", "segment_mb"])]
+    output_file: Option<String>,
+    /// Directory for timestamped recordings
+    #[arg(long, default_value = ".")]
+    output_dir: String,
+    /// File name of each recording without extension; `{date}`, `{time}` (UTC) and `{index}`
+    /// are replaced
+    #[arg(long, default_value = "{date}_{time}_{index}")]
+    name_template: String,
+    /// Start a new file after this many minutes of audio
+    #[arg(long, value_parser = parse_positive)]
+    segment_minutes: Option<f64>,
+    /// Start a new file after this many megabytes (10^6 bytes) of uncompressed audio data
+    #[arg(long, value_parser = parse_positive)]
+    segment_mb: Option<f64>,
+    /// File format of the recordings; FLAC needs integer samples and is lossless up to 24 bits
+    #[arg(long, value_enum, default_value_t = Codec::Wav)]
+    codec: Codec,
+    /// FLAC compression level from 0 (fastest) to 8 (smallest)
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u8).range(0..=8))]
+    compression_level: u8,
+    /// Seconds between header updates, bounding how much audio a crash can lose
+    #[arg(long, default_value_t = 10.0, value_parser = parse_positive)]
+    checkpoint_seconds: f64,
+    /// Seconds of audio buffered between capture and disk writes before frames are dropped
+    #[arg(long, default_value_t = 10.0)]
+    buffer_seconds: f64,
+    /// Identifier of this recording node stored in the files, the host name if omitted
+    #[arg(long)]
+    node_id: Option<String>,
+    /// Comma-separated channel names stored in the files, numbered if omitted
+    #[arg(long, value_delimiter = ',')]
+    channel_names: Vec<String>,
+    /// Input gain in dB stored in the files
+    #[arg(long, allow_negative_numbers = true)]
+    gain_db: Option<f32>,
+    /// Recording location stored in the files, as `<latitude>,<longitude>` in degrees
+    #[arg(long, value_parser = parse_gps, allow_hyphen_values = true)]
+    gps: Option<(f64, f64)>,
+    /// Input to record: `alsa`, a WAV or FLAC file, `-` for raw PCM on stdin or `synth`
+    #[arg(long, short = 'i', default_value = "alsa")]
+    input: String,
+    #[command(flatten)]
+    source: SourceArgs,
+}
+
+#[derive(clap::Args)]
+struct RepairArgs {
+    /// WAV files to repair in place
+    #[arg(required = true)]
+    files: Vec<String>,
 }
 
 #[derive(clap::Args)]
 struct GenCsvArgs {
-    #[arg(long, short = 'i')]
-    input_wav: String,
+    /// Input to read: a WAV or FLAC file, `-` for raw PCM on stdin, `alsa` or `synth`
+    #[arg(long, short = 'i', alias = "input-wav")]
+    input: String,
     #[arg(long, short = 'o')]
     output_csv: String,
+    #[command(flatten)]
+    features: FeatureArgs,
+    #[command(flatten)]
+    source: SourceArgs,
 }
 
 #[derive(clap::Args)]
 struct TestArgs {
     #[arg(long, short = 'm')]
     model_file: String,
+    /// Input to read: a WAV or FLAC file, `-` for raw PCM on stdin, `alsa` or `synth`
+    #[arg(long, short = 'i', alias = "input-wav")]
+    input: String,
+    /// Whether the input holds a drone, to score the predictions against
+    #[arg(long, short = 'd')]
+    drone: bool,
+    #[command(flatten)]
+    features: FeatureArgs,
+    #[command(flatten)]
+    source: SourceArgs,
+}
+
+#[derive(clap::Args)]
+struct HarmonicsArgs {
+    /// Input to read: a WAV or FLAC file, `-` for raw PCM on stdin, `alsa` or `synth`
     #[arg(long, short = 'i')]
-    input_wav: String,
+    input: String,
+    /// Whether the input holds a drone, to score the predictions against
     #[arg(long, short = 'd')]
     drone: bool,
+    /// Harmonics, counting the fundamental, a frame needs to count as a detection
+    #[arg(long, default_value_t = 4)]
+    min_harmonics: usize,
+    /// Harmonic-to-noise ratio in dB a frame needs to count as a detection
+    #[arg(long, default_value_t = -10.0, allow_negative_numbers = true)]
+    min_hnr: f32,
+    #[command(flatten)]
+    features: FeatureArgs,
+    #[command(flatten)]
+    source: SourceArgs,
+}
+
+#[derive(clap::Args)]
+struct DetectArgs {
+    #[arg(long, short = 'm')]
+    model_file: String,
+    /// Number of frames buffered between capture and inference before frames are dropped
+    #[arg(long, short = 'q', default_value_t = 8)]
+    queue_len: usize,
+    /// Input to classify: `alsa`, a WAV or FLAC file, `-` for raw PCM on stdin or `synth`
+    #[arg(long, short = 'i', default_value = "alsa")]
+    input: String,
+    /// Save a WAV clip and a JSON file with the predictions here for every detection
+    #[arg(long)]
+    clip_dir: Option<String>,
+    /// Seconds of audio before a detection included in its clip
+    #[arg(long, default_value_t = 5.0)]
+    pre_seconds: f64,
+    /// Seconds of audio after the last detection included in its clip
+    #[arg(long, default_value_t = 5.0)]
+    post_seconds: f64,
+    #[command(flatten)]
+    features: FeatureArgs,
+    #[command(flatten)]
+    source: SourceArgs,
 }
 
-fn record_audio(RecordArgs { output_file }: RecordArgs) {
+/// Opens a source whose samples are fed to feature extraction, resampled to the rate of
+/// `config`, refusing channels the source does not have.
+fn open_feature_source(
+    input: &str,
+    args: &SourceArgs,
+    channel: ChannelMode,
+    config: &FeatureConfig,
+) -> Result<Box<dyn AudioSource + Send>, SourceError> {
+    let mut audio = open_source(input, args)?;
+    let spec = audio.spec();
+    if spec.sample_rate != config.sample_rate {
+        println!(
+            "Resampling {} Hz input to {} Hz",
+            spec.sample_rate, config.sample_rate
+        );
+        audio = Box::new(Resampler::new(audio, config.sample_rate));
+    }
+    if let ChannelMode::Select(c) = channel
+        && c >= spec.channels as usize
+    {
+        return Err(SourceError::Unsupported(format!(
+            "channel {} of a {}-channel input",
+            c + 1,
+            spec.channels
+        )));
+    }
+    Ok(audio)
+}
+
+fn open_source(input: &str, args: &SourceArgs) -> Result<Box<dyn AudioSource + Send>, SourceError> {
+    if input == "-" {
+        let raw = RawSource::new(io::stdin(), args.format, args.channels, args.rate)?;
+        return Ok(Box::new(raw));
+    }
+    if input == "synth" {
+        let (spec, _) = source::pcm_format_spec(args.format, args.channels, args.rate)
+            .ok_or_else(|| SourceError::Unsupported(format!("synthetic format {}", args.format)))?;
+        return Ok(Box::new(SyntheticSource::new(
+            spec,
+            args.tone_hz,
+            args.tone_level,
+            args.noise_level,
+            args.duration,
+        )));
+    }
+    if input == "alsa" {
+        let audio = CaptureDevice::new(&args.device, args.channels as u32, args.rate, args.format)
+            .allow_rate_mismatch(args.allow_rate_mismatch);
+        return Ok(Box::new(audio.open()?));
+    }
+    source::open_file(input)
+}
+
+fn print_metadata(audio: &dyn AudioSource) {
+    if let Some(metadata) = audio.metadata() {
+        print!("{metadata}");
+    }
+}
+
+/// Clears `running` on SIGINT. The returned handle stops the handler thread once the work is
+/// done on its own.
+fn spawn_sigint_handler<'scope>(
+    s: &'scope Scope<'scope, '_>,
+    running: &'scope AtomicBool,
+) -> Handle {
+    let mut signals = Signals::new([SIGINT]).unwrap();
+    let handle = signals.handle();
+    s.spawn(move || {
+        for sig in signals.forever() {
+            if sig == signal_hook::consts::SIGINT {
+                running.store(false, Ordering::Relaxed);
+                println!();
+                break;
+            }
+        }
+    });
+    handle
+}
+
+fn parse_channel_mode(s: &str) -> Result<ChannelMode, String> {
+    match s {
+        "mix" => Ok(ChannelMode::Mix),
+        "each" => Ok(ChannelMode::Each),
+        _ => match s.parse::<usize>() {
+            Ok(c) if c > 0 => Ok(ChannelMode::Select(c - 1)),
+            _ => Err("expected a channel number counting from 1, `mix` or `each`".to_owned()),
+        },
+    }
+}
+
+fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        _ => Err("expected a positive number".to_owned()),
+    }
+}
+
+fn parse_gps(s: &str) -> Result<(f64, f64), String> {
+    bwf::parse_gps(s).ok_or_else(|| "expected <latitude>,<longitude> in degrees".to_owned())
+}
+
+fn record_audio(args: RecordArgs) {
+    let RecordArgs {
+        output_file,
+        input,
+        source,
+        ..
+    } = &args;
+    let output = match output_file {
+        Some(path) => Output::File(path.into()),
+        None => Output::Segments {
+            dir: args.output_dir.as_str().into(),
+            template: args.name_template.clone(),
+            max_seconds: args.segment_minutes.map(|m| m * 60.0),
+            max_bytes: args.segment_mb.map(|mb| (mb * 1e6) as u64),
+        },
+    };
+    let metadata = Metadata {
+        originator: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        device: if input == "alsa" {
+            source.device.clone()
+        } else {
+            input.clone()
+        },
+        node_id: args.node_id.clone().unwrap_or_else(|| {
+            fs::read_to_string("/etc/hostname")
+                .unwrap_or_default()
+                .trim()
+                .to_owned()
+        }),
+        channel_names: args.channel_names.clone(),
+        gain_db: args.gain_db,
+        gps: args.gps,
+        ..Default::default()
+    };
+    let settings = recorder::Settings {
+        encoding: match args.codec {
+            Codec::Wav => Encoding::Wav,
+            Codec::Flac => Encoding::Flac {
+                level: args.compression_level,
+            },
+        },
+        checkpoint_seconds: args.checkpoint_seconds,
+        buffer_seconds: args.buffer_seconds,
+    };
     let running = &AtomicBool::new(true);
+    let stats = &CaptureStats::default();
     thread::scope(|s| {
-        let mut signals = Signals::new([SIGINT]).unwrap();
-        s.spawn(move || {
-            for sig in signals.forever() {
-                if sig == signal_hook::consts::SIGINT {
-                    running.store(false, Ordering::Relaxed);
-                    println!();
-                    break;
-                }
-            }
-        });
+        let signals = spawn_sigint_handler(s, running);
         thread::Builder::new()
             .stack_size(1024 * 1024 * 8)
             .name("audio".to_owned())
             .spawn_scoped(s, move || {
-                let audio =
-                    CaptureDevice::new("hw:CARD=sndrpigooglevoi,DEV=0", 2, 48000, Format::s32());
-                match audio.read(output_file, running) {
+                let result = open_source(input, source).and_then(|mut audio| {
+                    recorder::record(
+                        audio.as_mut(),
+                        &output,
+                        &metadata,
+                        &settings,
+                        running,
+                        stats,
+                    )
+                });
+                match result {
                     Ok(()) => {}
                     Err(err) => {
                         println!("Audio error: {err}");
                     }
                 }
+                signals.close();
             })
             .unwrap();
     });
+    println!(
+        "Captured {} frames, dropped {} frames, {} overruns",
+        stats.frames.load(Ordering::Relaxed),
+        stats.dropped_frames.load(Ordering::Relaxed),
+        stats.xruns.load(Ordering::Relaxed)
+    );
     println!("clean exit");
 }
 
-fn gen_csv(GenCsvArgs { input_wav, output_csv }: GenCsvArgs) {
-    let mut csv = BufWriter::new(File::create(output_csv).unwrap());
-    let mut reader = hound::WavReader::open(input_wav).unwrap();
-    let mut samples = Vec::with_capacity(8192);
-
-    for sample in reader.samples::<i32>() {
-        let s = sample.unwrap();
-        samples.push(s);
-        if samples.len() == 8192 {
-            let (_, values) = models::process_samples(samples.iter());
-
-            write!(csv, "{}", values[0]).unwrap();
-            for v in &values[1..] {
-                write!(csv, ",{v}").unwrap();
+fn repair(RepairArgs { files }: RepairArgs) {
+    for file in files {
+        match wav::repair(&file) {
+            Ok(None) => println!("{file}: header is intact"),
+            Ok(Some(info)) => {
+                let frames = info.data_len
+                    / (wav::sample_width(&info.spec) as u64 * info.spec.channels as u64);
+                println!(
+                    "{file}: repaired, {frames} frames ({:.1} s)",
+                    frames as f64 / info.spec.sample_rate as f64
+                );
             }
-            writeln!(csv).unwrap();
-
-            samples.clear();
+            Err(err) => println!("{file}: {err}"),
         }
     }
 }
 
-fn test(TestArgs { model_file, input_wav, drone }: TestArgs) {
-    let mut detection_model = models::load_onnx(model_file);
+fn gen_csv(
+    GenCsvArgs {
+        input,
+        output_csv,
+        features,
+        source,
+    }: GenCsvArgs,
+) {
+    let config = features.config(None).unwrap();
+    let config_path = Path::new(&output_csv).with_extension("features.json");
+    config.save(&config_path).unwrap();
+    println!("Feature settings written to {}", config_path.display());
 
-    let mut detections: CircularBuffer<10, u8> = CircularBuffer::from([0; 10]);
+    let freqs = models::frequencies(&config);
+    let bins_path = Path::new(&output_csv).with_extension("bins.csv");
+    let bins = freqs.iter().map(f32::to_string).collect::<Vec<_>>();
+    fs::write(&bins_path, bins.join(",") + "\n").unwrap();
+    println!(
+        "{} {} features per frame, their frequencies written to {}",
+        freqs.len(),
+        config.kind,
+        bins_path.display()
+    );
 
-    let mut reader = hound::WavReader::open(input_wav).unwrap();
+    let mut csv = BufWriter::new(File::create(output_csv).unwrap());
+    let channel = features.channel;
+    let mut audio = open_feature_source(&input, &source, channel, &config).unwrap();
+    print_metadata(audio.as_ref());
+    let spec = audio.spec();
+    let channels = spec.channels as usize;
+    let mut framer = framer(&config, spec);
+    let mut extractors: Vec<_> = (0..channel.rows(channels))
+        .map(|_| models::Extractor::new(&config))
+        .collect();
+
+    while let Some(chunk) = audio.read().unwrap() {
+        framer.push(&chunk, |frame| {
+            channel.split(frame, channels, |row, samples| {
+                let Some((_, values)) = extractors[row].process(samples.iter(), spec.sample_rate)
+                else {
+                    return;
+                };
+
+                write!(csv, "{}", values[0]).unwrap();
+                for v in &values[1..] {
+                    write!(csv, ",{v}").unwrap();
+                }
+                writeln!(csv).unwrap();
+            });
+        });
+    }
+}
+
+/// Runs `decide` on every frame of `audio` for each signal of `channel` and prints the
+/// voted prediction, then the share of predictions matching `drone`. `decide` gets the row
+/// and its samples and returns whether the frame holds a drone with a description, or
+/// nothing while it has no decision yet.
+fn evaluate(
+    audio: &mut dyn AudioSource,
+    config: &FeatureConfig,
+    channel: ChannelMode,
+    drone: bool,
+    mut decide: impl FnMut(usize, &[i32]) -> Option<(bool, String)>,
+) {
+    let spec = audio.spec();
+    let channels = spec.channels as usize;
+
+    // Votes are kept per signal, so separate channels do not outvote each other.
+    let mut detections: Vec<CircularBuffer<10, u8>> =
+        vec![CircularBuffer::from([0; 10]); channel.rows(channels)];
 
-    let mut samples = Vec::with_capacity(8192);
+    let mut framer = framer(config, spec);
 
     let mut predictions = 0;
     let mut correct = 0;
 
-    for sample in reader.samples::<i32>() {
-        let s = sample.unwrap();
-        samples.push(s);
-        if samples.len() == 8192 {
-            let (_, values) = models::process_samples(samples.iter());
-            samples.clear();
-            let x = Array2::from_shape_vec((1, values.len()), values).unwrap();
-            let mut run =
-                detection_model.run(inputs![TensorRef::from_array_view(x.view()).unwrap()]);
-            let Ok(ref mut outputs) = run else {
-                continue;
-            };
-            let prob = outputs.remove("output_probability").unwrap();
-            let pred = outputs["output_label"]
-                .try_extract_tensor::<i64>()
-                .unwrap()
-                .1;
-            detections.push_back(pred[0] as u8);
-            drop(run);
-            let pred = detections.back().unwrap();
-            let prob: Sequence<DynMapValueType> = prob.into_dyn().downcast().unwrap();
-            let prob = prob.extract_sequence(detection_model.allocator());
-            let prob = prob
-                .iter()
-                .map(|p| p.try_extract_map::<i64, f32>().unwrap())
-                .collect::<Vec<HashMap<i64, f32>>>();
-            let prob = &prob[0].get(&(*pred as i64)).unwrap();
-
-            let drone_predicted = detections.iter().sum::<u8>() > 1;
-            predictions += 1;
-            if drone_predicted == drone {
-                correct += 1;
+    while let Some(chunk) = audio.read().unwrap() {
+        framer.push(&chunk, |frame| {
+            channel.split(frame, channels, |row, samples| {
+                let Some((detected, description)) = decide(row, samples) else {
+                    return;
+                };
+                let detections = &mut detections[row];
+                detections.push_back(detected as u8);
+
+                let drone_predicted = detections.iter().sum::<u8>() > 1;
+                predictions += 1;
+                if drone_predicted == drone {
+                    correct += 1;
+                }
+                if channel == ChannelMode::Each {
+                    print!("Channel {} | ", row + 1);
+                }
+                println!("Drone predicted: {drone_predicted} | Drone detected: {description}");
+            });
+        });
+    }
+
+    if predictions == 0 {
+        println!("Acc: no frames classified");
+    } else {
+        println!("Acc: {}", correct as f32 / predictions as f32);
+    }
+}
+
+fn test(
+    TestArgs {
+        model_file,
+        input,
+        drone,
+        features,
+        source,
+    }: TestArgs,
+) {
+    let mut detection_model = models::load_onnx(model_file);
+    let config = features.config(Some(&detection_model)).unwrap();
+    let feature_len = models::frequencies(&config).len();
+    let shape = models::input_shape(&detection_model, feature_len, config.context_frames).unwrap();
+
+    let channel = features.channel;
+    let mut audio = open_feature_source(&input, &source, channel, &config).unwrap();
+    print_metadata(audio.as_ref());
+    let spec = audio.spec();
+
+    let mut extractors: Vec<_> = (0..channel.rows(spec.channels as usize))
+        .map(|_| models::Extractor::new(&config))
+        .collect();
+
+    evaluate(audio.as_mut(), &config, channel, drone, |row, samples| {
+        let (_, values) = extractors[row].process(samples.iter(), spec.sample_rate)?;
+        let (pred, prob) = models::classify(&mut detection_model, values, &shape)?;
+        Some((pred != 0, format!("{pred} | confidence = {prob:?}")))
+    });
+}
+
+fn harmonics(args: HarmonicsArgs) {
+    let mut config = args.features.config(None).unwrap();
+    // The analysis runs on the harmonic settings whatever features the file was written for.
+    config.kind = features::Kind::Harmonic;
+    config.validate().unwrap();
+
+    let channel = args.features.channel;
+    let mut audio = open_feature_source(&args.input, &args.source, channel, &config).unwrap();
+    print_metadata(audio.as_ref());
+    let sample_rate = audio.spec().sample_rate;
+
+    evaluate(
+        audio.as_mut(),
+        &config,
+        channel,
+        args.drone,
+        |_, samples| {
+            let series = models::harmonics(samples.iter(), sample_rate, &config);
+            let detected = series.count >= args.min_harmonics && series.hnr >= args.min_hnr;
+            Some((
+                detected,
+                format!(
+                    "{detected} | fundamental = {:.1} Hz, harmonics = {}, HNR = {:.1} dB",
+                    series.fundamental, series.count, series.hnr
+                ),
+            ))
+        },
+    );
+}
+
+fn detect(
+    DetectArgs {
+        model_file,
+        queue_len,
+        input,
+        clip_dir,
+        pre_seconds,
+        post_seconds,
+        features,
+        source,
+    }: DetectArgs,
+) {
+    let running = &AtomicBool::new(true);
+    let overruns = &AtomicUsize::new(0);
+    // Each frame comes with the number of frames dropped right before it.
+    let (tx, rx) = mpsc::sync_channel::<(Vec<i32>, usize)>(queue_len);
+    let channel = features.channel;
+
+    let mut detection_model = models::load_onnx(model_file);
+    let config = match features.config(Some(&detection_model)) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Feature settings error: {err}");
+            return;
+        }
+    };
+    let feature_len = models::frequencies(&config).len();
+    let shape = match models::input_shape(&detection_model, feature_len, config.context_frames) {
+        Ok(shape) => shape,
+        Err(err) => {
+            println!("Model error: {err}");
+            return;
+        }
+    };
+
+    let mut audio = match open_feature_source(&input, &source, channel, &config) {
+        Ok(audio) => audio,
+        Err(err) => {
+            println!("Audio error: {err}");
+            return;
+        }
+    };
+    let spec = audio.spec();
+    let channels = spec.channels as usize;
+    let mut clips = None;
+    if let Some(dir) = clip_dir {
+        match ClipRecorder::new(
+            dir.into(),
+            spec,
+            config.hop * channels,
+            pre_seconds,
+            post_seconds,
+        ) {
+            Ok(recorder) => clips = Some(recorder),
+            Err(err) => {
+                println!("Clip error: {err}");
+                return;
             }
-            println!(
-                "Drone predicted: {drone_predicted} | Drone detected: {pred} | confidence = {prob:?}"
-            );
         }
     }
 
-    println!("Acc: {}", correct as f32 / predictions as f32);
+    let config = &config;
+    thread::scope(|s| {
+        let signals = spawn_sigint_handler(s, running);
+        thread::Builder::new()
+            .stack_size(1024 * 1024 * 8)
+            .name("audio".to_owned())
+            .spawn_scoped(s, move || {
+                let mut result = Ok(());
+                // Offline sources wait for inference instead of dropping frames.
+                let live = audio.is_live();
+                let mut framer = framer(config, spec);
+                let mut dropped = 0;
+                while running.load(Ordering::Relaxed) {
+                    let chunk = match audio.read() {
+                        Ok(Some(chunk)) => chunk,
+                        Ok(None) => break,
+                        Err(err) => {
+                            result = Err(err);
+                            break;
+                        }
+                    };
+                    framer.push(&chunk, |frame| {
+                        if !live {
+                            if tx.send((frame.to_vec(), 0)).is_err() {
+                                running.store(false, Ordering::Relaxed);
+                            }
+                            return;
+                        }
+                        match tx.try_send((frame.to_vec(), dropped)) {
+                            Ok(()) => dropped = 0,
+                            Err(TrySendError::Full(_)) => {
+                                dropped += 1;
+                                let n = overruns.fetch_add(1, Ordering::Relaxed) + 1;
+                                println!("Inference queue overrun, {n} frames dropped so far");
+                            }
+                            Err(TrySendError::Disconnected(_)) => {
+                                running.store(false, Ordering::Relaxed);
+                            }
+                        }
+                    });
+                }
+                drop(tx);
+                if let Err(err) = result {
+                    println!("Audio error: {err}");
+                }
+                signals.close();
+            })
+            .unwrap();
+        thread::Builder::new()
+            .name("inference".to_owned())
+            .spawn_scoped(s, move || {
+                let mut detections: Vec<CircularBuffer<10, u8>> =
+                    vec![CircularBuffer::from([0; 10]); channel.rows(channels)];
+                let mut extractors: Vec<_> = (0..channel.rows(channels))
+                    .map(|_| models::Extractor::new(config))
+                    .collect();
+                let start = Instant::now();
+                for (frame, dropped) in rx {
+                    if let Some(clips) = clips.as_mut()
+                        && let Err(err) = clips.skip(dropped)
+                    {
+                        println!("Clip error: {err}");
+                    }
+                    // With several channels, the frame counts as a detection if any of them
+                    // does, and its clip records the highest label with the highest confidence.
+                    let mut drone_predicted = false;
+                    let mut prediction: Option<(i64, f32)> = None;
+                    channel.split(&frame, channels, |row, samples| {
+                        let Some((_, values)) =
+                            extractors[row].process(samples.iter(), spec.sample_rate)
+                        else {
+                            return;
+                        };
+                        let row_prediction = models::classify(&mut detection_model, values, &shape);
+                        let detections = &mut detections[row];
+                        if let Some((pred, _)) = row_prediction {
+                            detections.push_back(pred as u8);
+                        }
+
+                        let row_predicted = detections.iter().sum::<u8>() > 1;
+                        if let Some((pred, prob)) = row_prediction {
+                            let label = match channel {
+                                ChannelMode::Each => format!("Channel {} | ", row + 1),
+                                _ => String::new(),
+                            };
+                            println!(
+                                "[{:.2}s] {label}Drone predicted: {row_predicted} | Drone detected: {pred} | confidence = {prob:?}",
+                                start.elapsed().as_secs_f32()
+                            );
+                        }
+                        drone_predicted |= row_predicted;
+                        if row_prediction > prediction {
+                            prediction = row_prediction;
+                        }
+                    });
+                    if let Some(clips) = clips.as_mut()
+                        && let Err(err) = clips.push(frame, prediction, drone_predicted)
+                    {
+                        println!("Clip error: {err}");
+                    }
+                }
+                if let Some(clips) = clips
+                    && let Err(err) = clips.finish()
+                {
+                    println!("Clip error: {err}");
+                }
+            })
+            .unwrap();
+    });
+    println!(
+        "Inference queue overruns: {}",
+        overruns.load(Ordering::Relaxed)
+    );
+    println!("clean exit");
 }
 
 fn main() {
@@ -179,6 +844,20 @@ fn main() {
         Commands::Test(args) => {
             test(args);
         }
+        Commands::Detect(args) => {
+            detect(args);
+        }
+        Commands::Harmonics(args) => {
+            harmonics(args);
+        }
+        Commands::Repair(args) => {
+            repair(args);
+        }
+        Commands::ListDevices => {
+            if let Err(err) = audio::list_devices() {
+                println!("ALSA error: {err}");
+            }
+        }
     }
 
     // let mut detection_model = models::load_onnx("detection.onnx");
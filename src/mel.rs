@@ -0,0 +1,97 @@
+//! Mel filterbank: FFT magnitudes summed into triangular bands spaced evenly on the mel scale,
+//! which follows the pitch resolution of hearing, then compressed logarithmically.
+
+use crate::features::{FeatureConfig, Log};
+
+/// Smallest band energy, keeping the logarithm of silent bands finite.
+const FLOOR: f32 = 1e-10;
+
+/// Mel value of `hz`, by the HTK formula.
+pub fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+pub fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// Corners of the bands of `config` in Hz: band `b` rises from `edges[b]` to its peak at
+/// `edges[b + 1]` and falls back to zero at `edges[b + 2]`.
+pub fn band_edges(config: &FeatureConfig) -> Vec<f32> {
+    let low = hz_to_mel(config.min_freq);
+    let high = hz_to_mel(config.max_freq);
+    let steps = config.mel_bands + 1;
+    (0..=steps)
+        .map(|i| mel_to_hz(low + (high - low) * i as f32 / steps as f32))
+        .collect()
+}
+
+/// Log-mel energies of FFT `magnitudes` at `freqs`, of samples in `[-1, 1]`, together with
+/// the centre frequencies of the bands.
+pub fn log_mel(freqs: &[f32], magnitudes: &[f32], config: &FeatureConfig) -> (Vec<f32>, Vec<f32>) {
+    let edges = band_edges(config);
+    let energies = edges
+        .windows(3)
+        .map(|corners| {
+            let [low, centre, high] = [corners[0], corners[1], corners[2]];
+            let energy = freqs
+                .iter()
+                .zip(magnitudes)
+                .map(|(&f, &m)| {
+                    let weight = ((f - low) / (centre - low))
+                        .min((high - f) / (high - centre))
+                        .max(0.0);
+                    weight * m.powf(config.mel_power)
+                })
+                .sum::<f32>();
+            // Normalised to unit area, so wide high bands do not outweigh narrow low ones.
+            energy * 2.0 / (high - low)
+        })
+        .map(|energy| match config.mel_log {
+            Log::Db => 10.0 * energy.max(FLOOR).log10(),
+            Log::Natural => energy.max(FLOOR).ln(),
+            Log::None => energy,
+        })
+        .collect();
+    (edges[1..=config.mel_bands].to_vec(), energies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mel_scale_round_trips() {
+        assert!((hz_to_mel(1000.0) - 1000.0).abs() < 0.1);
+        for hz in [0.0, 100.0, 1000.0, 8000.0] {
+            assert!((mel_to_hz(hz_to_mel(hz)) - hz).abs() < 0.01 * hz.max(1.0));
+        }
+    }
+
+    #[test]
+    fn tone_lands_in_the_band_around_it() {
+        let config = FeatureConfig::default();
+        let freqs = (1..=2000).map(|i| i as f32 * 2.0).collect::<Vec<_>>();
+        for tone in [150.0, 1000.0, 3000.0] {
+            let magnitudes = freqs
+                .iter()
+                .map(|f| if *f == tone { 1.0 } else { 0.0 })
+                .collect::<Vec<_>>();
+            let (centres, energies) = log_mel(&freqs, &magnitudes, &config);
+            assert_eq!(centres.len(), config.mel_bands);
+            let loudest = (0..energies.len())
+                .max_by(|a, b| energies[*a].total_cmp(&energies[*b]))
+                .unwrap();
+            let nearest = (0..centres.len())
+                .min_by(|a, b| {
+                    (centres[*a] - tone)
+                        .abs()
+                        .total_cmp(&(centres[*b] - tone).abs())
+                })
+                .unwrap();
+            assert_eq!(loudest, nearest, "{tone} Hz");
+            let edges = band_edges(&config);
+            assert!(edges[loudest] < tone && tone < edges[loudest + 2]);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Mel-frequency cepstral coefficients: the DCT of the log-mel energies, describing the
+//! spectral envelope in a few mostly uncorrelated values.
+
+use std::f32::consts::PI;
+
+/// First `coefficients` terms of the orthonormal DCT-II of `log_mel`.
+pub fn mfcc(log_mel: &[f32], coefficients: usize) -> Vec<f32> {
+    let bands = log_mel.len() as f32;
+    (0..coefficients)
+        .map(|k| {
+            let scale = if k == 0 { 1.0 } else { 2.0 };
+            let sum = log_mel
+                .iter()
+                .enumerate()
+                .map(|(i, e)| e * (PI * k as f32 * (i as f32 + 0.5) / bands).cos())
+                .sum::<f32>();
+            (scale / bands).sqrt() * sum
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_log_mel_has_only_a_mean_term() {
+        let coefficients = mfcc(&[3.0; 20], 13);
+        assert_eq!(coefficients.len(), 13);
+        assert!((coefficients[0] - 3.0 * 20f32.sqrt()).abs() < 1e-4);
+        assert!(coefficients[1..].iter().all(|c| c.abs() < 1e-4));
+    }
+
+    #[test]
+    fn cosine_log_mel_excites_its_coefficient() {
+        let bands = 32;
+        let log_mel = (0..bands)
+            .map(|i| (PI * 4.0 * (i as f32 + 0.5) / bands as f32).cos())
+            .collect::<Vec<_>>();
+        let coefficients = mfcc(&log_mel, 8);
+        for (k, c) in coefficients.iter().enumerate() {
+            let expected = if k == 4 {
+                (bands as f32 / 2.0).sqrt()
+            } else {
+                0.0
+            };
+            assert!((c - expected).abs() < 1e-4, "c{k} = {c}");
+        }
+    }
+}
@@ -1,33 +1,279 @@
-use std::path::Path;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+};
 
-use ndarray::{Array, Array1};
+use ndarray::{Array, Array1, ArrayD, IxDyn};
 use ndarray_conv::ConvExt;
-use ort::session::Session;
-use spectrum_analyzer::{samples_fft_to_spectrum, windows::hann_window};
+use ort::{
+    inputs,
+    session::Session,
+    value::{DynMapValueType, Sequence, TensorRef},
+};
+use spectrum_analyzer::{
+    samples_fft_to_spectrum,
+    windows::{blackman_harris_4term, hann_window},
+};
 
-pub fn process_samples<'a, I: Iterator<Item = &'a i32>>(samples: I) -> (Vec<f32>, Vec<f32>) {
+use crate::{
+    features::{self, FeatureConfig, Kind, Scaling, Window},
+    harmonic::{self, Harmonics},
+    mel, mfcc,
+    resample::bessel_i0,
+    summary,
+};
+
+/// Sample rate the spectral features and the models trained on them assume.
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// Magnitude of a full-scale 32-bit sample.
+const FULL_SCALE: f32 = 2147483648.0;
+
+/// Coefficients of the flat-top window, as used by Matlab `flattopwin`.
+const FLAT_TOP: [f64; 5] = [
+    0.21557895,
+    0.41663158,
+    0.277263158,
+    0.083578947,
+    0.006947368,
+];
+
+/// Sum of cosines `a[0] - a[1] cos(x) + a[2] cos(2x) - ...` over a period of `samples`.
+fn cosine_window(samples: &[f32], a: &[f64]) -> Vec<f32> {
+    let len = samples.len() as f64;
+    samples
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let x = 2.0 * std::f64::consts::PI * i as f64 / len;
+            let w = a
+                .iter()
+                .enumerate()
+                .map(|(k, &a)| if k % 2 == 0 { a } else { -a } * (k as f64 * x).cos())
+                .sum::<f64>();
+            s * w as f32
+        })
+        .collect()
+}
+
+/// Kaiser window over a period of `samples`, matching the periodic cosine windows.
+fn kaiser_window(samples: &[f32], beta: f32) -> Vec<f32> {
+    let beta = beta as f64;
+    let len = samples.len() as f64;
+    let norm = bessel_i0(beta);
+    samples
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let x = 2.0 * i as f64 / len - 1.0;
+            s * (bessel_i0(beta * (1.0 - x * x).sqrt()) / norm) as f32
+        })
+        .collect()
+}
+
+/// Applies the window of `config` and zero-pads the result to its FFT length.
+fn window(samples: &[f32], config: &FeatureConfig) -> Vec<f32> {
+    let mut windowed = match config.window {
+        Window::Hann => hann_window(samples),
+        // The Hamming window of spectrum_analyzer takes the cosine of the wrong term.
+        Window::Hamming => cosine_window(samples, &[0.54, 0.46]),
+        Window::BlackmanHarris => blackman_harris_4term(samples),
+        Window::FlatTop => cosine_window(samples, &FLAT_TOP),
+        Window::Kaiser => kaiser_window(samples, config.kaiser_beta),
+    };
+    windowed.resize(config.fft_len(), 0.0);
+    windowed
+}
+
+/// Computes the features of a frame of full-scale 32-bit samples taken at `sample_rate`,
+/// returned together with the frequencies they belong to: those of the FFT bins or grid
+/// points, or the band centres.
+pub fn process_samples<'a, I: Iterator<Item = &'a i32>>(
+    samples: I,
+    sample_rate: u32,
+    config: &FeatureConfig,
+) -> (Vec<f32>, Vec<f32>) {
+    let samples = samples.map(|s| *s as f32).collect::<Vec<_>>();
+    match config.kind {
+        Kind::Spectrum => spectrum_difference(&samples, sample_rate, config),
+        Kind::LogMel => log_mel(&samples, sample_rate, config),
+        Kind::Mfcc => {
+            let (_, log_mel) = log_mel(&samples, sample_rate, config);
+            let coefficients = config.mfcc_coefficients;
+            let indices = (0..coefficients).map(|k| k as f32).collect();
+            (indices, mfcc::mfcc(&log_mel, coefficients))
+        }
+        Kind::Harmonic => {
+            let values = harmonic_series(&samples, sample_rate, config).to_vec();
+            ((0..values.len()).map(|i| i as f32).collect(), values)
+        }
+        Kind::Summary => {
+            let (min_freq, max_freq) = (config.min_freq, config.max_freq);
+            let (freqs, values) = magnitudes(&samples, sample_rate, config, min_freq, max_freq);
+            // Scaled to samples in [-1, 1], keeping the higher moments within range.
+            let values = values.iter().map(|v| v / FULL_SCALE).collect::<Vec<_>>();
+            let power = samples
+                .iter()
+                .map(|s| (s / FULL_SCALE).powi(2))
+                .sum::<f32>();
+            let rms = (power / samples.len() as f32).sqrt();
+            let values = summary::summary(&freqs, &values, rms, config);
+            ((0..values.len()).map(|i| i as f32).collect(), values)
+        }
+    }
+}
+
+/// Strongest harmonic series in a frame of full-scale 32-bit samples taken at `sample_rate`.
+pub fn harmonics<'a, I: Iterator<Item = &'a i32>>(
+    samples: I,
+    sample_rate: u32,
+    config: &FeatureConfig,
+) -> Harmonics {
     let samples = samples.map(|s| *s as f32).collect::<Vec<_>>();
-    let hann_window = hann_window(&samples);
+    harmonic_series(&samples, sample_rate, config)
+}
 
+fn harmonic_series(samples: &[f32], sample_rate: u32, config: &FeatureConfig) -> Harmonics {
+    let (min_freq, max_freq) = (config.min_freq, config.max_freq);
+    let (freqs, values) = magnitudes(samples, sample_rate, config, min_freq, max_freq);
+    harmonic::analyze(&freqs, &values, config)
+}
+
+/// Computes features of consecutive frames of one signal, followed by the deltas of
+/// `config`. Those are regressions over the `2 * delta_width + 1` vectors centred on their
+/// frame, so each order needs `delta_width` frames after it. The vector returned for a frame
+/// therefore describes the frame `deltas * delta_width` frames back, features and deltas
+/// alike. The vectors of the last `context_frames` frames are then stacked, oldest first.
+///
+/// Nothing is returned for the first frames, until there are enough of them for every delta
+/// and every stacked frame.
+pub struct Extractor {
+    config: FeatureConfig,
+    /// The last `2 * delta_width + 1` vectors of each order below the highest, oldest first.
+    history: Vec<VecDeque<Vec<f32>>>,
+    /// Vectors of the frames stacked into the output, oldest first.
+    context: VecDeque<Vec<f32>>,
+}
+
+impl Extractor {
+    pub fn new(config: &FeatureConfig) -> Self {
+        Self {
+            config: config.clone(),
+            history: vec![VecDeque::new(); config.deltas],
+            context: VecDeque::new(),
+        }
+    }
+
+    /// Features of the next frame, as `process_samples` returns them with the deltas
+    /// appended and the frames before stacked in front, their frequencies repeated for each
+    /// order and frame. `None` while the frames so far are too few.
+    pub fn process<'a, I: Iterator<Item = &'a i32>>(
+        &mut self,
+        samples: I,
+        sample_rate: u32,
+    ) -> Option<(Vec<f32>, Vec<f32>)> {
+        let (freqs, values) = process_samples(samples, sample_rate, &self.config);
+        let width = self.config.delta_width;
+        let mut current = values;
+        for history in &mut self.history {
+            history.push_back(current);
+            if history.len() > 2 * width + 1 {
+                history.pop_front();
+            } else if history.len() < 2 * width + 1 {
+                return None;
+            }
+            let norm = 2.0 * (1..=width).map(|n| (n * n) as f32).sum::<f32>();
+            current = (0..freqs.len())
+                .map(|j| {
+                    (1..=width)
+                        .map(|n| n as f32 * (history[width + n][j] - history[width - n][j]))
+                        .sum::<f32>()
+                        / norm
+                })
+                .collect();
+        }
+        // The vector of order `k` computed `(deltas - k) * width` frames ago was centred on the
+        // same frame as the newest order, and the history still holds it.
+        let deltas = self.config.deltas;
+        let all_freqs = freqs.repeat(deltas + 1);
+        let mut all_values = Vec::with_capacity(all_freqs.len());
+        for (k, history) in self.history.iter().enumerate() {
+            all_values.extend_from_slice(&history[(2 + k - deltas) * width]);
+        }
+        all_values.extend_from_slice(&current);
+
+        let frames = self.config.context_frames;
+        self.context.push_back(all_values);
+        if self.context.len() > frames {
+            self.context.pop_front();
+        } else if self.context.len() < frames {
+            return None;
+        }
+        let stacked = self.context.iter().flatten().copied().collect();
+        Some((all_freqs.repeat(frames), stacked))
+    }
+}
+
+/// FFT magnitudes of the windowed frame from `min_freq` to `max_freq`, with the frequencies
+/// of their bins.
+fn magnitudes(
+    samples: &[f32],
+    sample_rate: u32,
+    config: &FeatureConfig,
+    min_freq: f32,
+    max_freq: f32,
+) -> (Vec<f32>, Vec<f32>) {
+    let windowed = window(samples, config);
     let spectrum = samples_fft_to_spectrum(
-        &hann_window,
-        48000,
-        spectrum_analyzer::FrequencyLimit::Range(5.0, 4000.0),
+        &windowed,
+        sample_rate,
+        spectrum_analyzer::FrequencyLimit::Range(min_freq, max_freq),
         // spectrum_analyzer::FrequencyLimit::All,
         None,
     )
     .unwrap();
 
-    // let frequencies: Vec<f32> = (5..=4000).map(|s| s as f32).collect();
-
     let (freqs, values): (Vec<_>, Vec<_>) = spectrum.data().iter().copied().unzip();
     let freqs: Vec<f32> = freqs.into_iter().map(|f| f.val()).collect();
-
     let values: Vec<f32> = values.iter().map(|s| s.val().abs()).collect();
+    (freqs, values)
+}
+
+/// Log-mel energies of the frame with the centre frequencies of their bands.
+fn log_mel(samples: &[f32], sample_rate: u32, config: &FeatureConfig) -> (Vec<f32>, Vec<f32>) {
+    let (min_freq, max_freq) = (config.min_freq, config.max_freq);
+    let (freqs, values) = magnitudes(samples, sample_rate, config, min_freq, max_freq);
+    // Scaled to samples in [-1, 1], the range log-mel features are usually taken over.
+    let values = values.iter().map(|v| v / FULL_SCALE).collect::<Vec<_>>();
+    mel::log_mel(&freqs, &values, config)
+}
+
+/// FFT magnitudes minus their moving average, scaled and interpolated onto the grid as
+/// `config` asks.
+fn spectrum_difference(
+    samples: &[f32],
+    sample_rate: u32,
+    config: &FeatureConfig,
+) -> (Vec<f32>, Vec<f32>) {
+    let grid = config.grid();
+    let (mut min_freq, mut max_freq) = (config.min_freq, config.max_freq);
+    if grid.is_some() {
+        // One bin more on each side, so the ends of the grid lie between bins.
+        let bin = sample_rate as f32 / config.fft_len() as f32;
+        min_freq = (min_freq - bin).max(0.0);
+        max_freq = (max_freq + bin).min(sample_rate as f32 / 2.0);
+    }
+    let (freqs, values) = magnitudes(samples, sample_rate, config, min_freq, max_freq);
+
     let input = Array1::from_shape_vec(values.len(), values.clone()).unwrap();
-    let kernel: Array1<f32> = Array::from_shape_vec(21, vec![1.0 / 21.0; 21]).unwrap();
+    let taps = config.smoothing_taps;
+    let kernel: Array1<f32> = Array::from_shape_vec(taps, vec![1.0 / taps as f32; taps]).unwrap();
     let output = input
-        .conv(&kernel, ndarray_conv::ConvMode::Same, ndarray_conv::PaddingMode::Zeros)
+        .conv(
+            &kernel,
+            ndarray_conv::ConvMode::Same,
+            ndarray_conv::PaddingMode::Zeros,
+        )
         .unwrap();
     // let conv_layer = ConvolutionLayer::new(kernel, None, 1, convolutions_rs::Padding::Same);
     // let output_layer: Array3<f32> = conv_layer.convolve(&input);
@@ -38,21 +284,55 @@ pub fn process_samples<'a, I: Iterator<Item = &'a i32>>(samples: I) -> (Vec<f32>
         .zip(output.iter())
         .map(|(v, a)| v - a)
         .collect::<Vec<f32>>();
-    let min_diff = *fft_diff.iter().min_by(|a, b| a.total_cmp(b)).unwrap();
-    let max_diff = *fft_diff.iter().max_by(|a, b| a.total_cmp(b)).unwrap();
+    if config.scaling == Scaling::MinMax {
+        let min_diff = *fft_diff.iter().min_by(|a, b| a.total_cmp(b)).unwrap();
+        let max_diff = *fft_diff.iter().max_by(|a, b| a.total_cmp(b)).unwrap();
 
-    if max_diff > min_diff {
-        fft_diff
-            .iter_mut()
-            .for_each(|s| *s = 2.0 * (*s - min_diff) / (max_diff - min_diff) - 1.0);
-    } else {
-        fft_diff = vec![0.0; fft_diff.len()];
+        if max_diff > min_diff {
+            fft_diff
+                .iter_mut()
+                .for_each(|s| *s = 2.0 * (*s - min_diff) / (max_diff - min_diff) - 1.0);
+        } else {
+            fft_diff = vec![0.0; fft_diff.len()];
+        }
     }
 
-    // let interp_fft_diff = interp_slice(&freqs, &fft_diff, &frequencies, &InterpMode::default());
-    // assert_eq!(interp_fft_diff.len(), 3996);
+    match grid {
+        Some(grid) => {
+            let values = interpolate(&freqs, &fft_diff, &grid);
+            (grid, values)
+        }
+        None => (freqs, fft_diff),
+    }
+}
+
+/// Linearly interpolates `values` at ascending `freqs` onto ascending `grid`, holding the
+/// end values beyond the first and last frequency.
+fn interpolate(freqs: &[f32], values: &[f32], grid: &[f32]) -> Vec<f32> {
+    let mut i = 0;
+    grid.iter()
+        .map(|&f| {
+            while i + 1 < freqs.len() && freqs[i + 1] < f {
+                i += 1;
+            }
+            if i + 1 == freqs.len() || f <= freqs[i] {
+                return values[i];
+            }
+            let t = (f - freqs[i]) / (freqs[i + 1] - freqs[i]);
+            values[i] + (values[i + 1] - values[i]) * t
+        })
+        .collect()
+}
 
-    (freqs, fft_diff)
+/// Feature settings stored in the metadata of a model, if any.
+pub fn feature_config(session: &Session) -> Result<Option<FeatureConfig>, String> {
+    let json = session
+        .metadata()
+        .and_then(|metadata| metadata.custom(features::METADATA_KEY))
+        .map_err(|err| format!("cannot read model metadata: {err}"))?;
+    json.map(|json| FeatureConfig::parse(&json))
+        .transpose()
+        .map_err(|err| format!("invalid feature settings in model metadata: {err}"))
 }
 
 pub fn load_onnx<P: AsRef<Path>>(model_path: P) -> Session {
@@ -62,6 +342,114 @@ pub fn load_onnx<P: AsRef<Path>>(model_path: P) -> Session {
         .unwrap()
 }
 
+/// Frequencies of the features `Extractor` computes with `config`, which only depend on the
+/// settings.
+pub fn frequencies(config: &FeatureConfig) -> Vec<f32> {
+    let silence = vec![0; config.frame_len];
+    let mut extractor = Extractor::new(config);
+    loop {
+        if let Some((freqs, _)) = extractor.process(silence.iter(), config.sample_rate) {
+            return freqs;
+        }
+    }
+}
+
+/// Shape of the input of `session` holding `len` feature values stacked from `frames`
+/// frames: `1×len` for models taking a flat vector, `frames×n` for models taking a matrix
+/// with a row per frame, and `1×frames×n` or `1×1×frames×n` for models taking a sequence or
+/// an image of frames.
+pub fn input_shape(session: &Session, len: usize, frames: usize) -> Result<Vec<usize>, String> {
+    let expected = session
+        .inputs
+        .first()
+        .and_then(|input| input.input_type.tensor_shape())
+        .ok_or("model input is not a tensor")?;
+    shape_for(expected, len, frames)
+}
+
+/// Layout of `len` values from `frames` frames for a model input of shape `expected`, in
+/// which open dimensions are negative.
+fn shape_for(expected: &[i64], len: usize, frames: usize) -> Result<Vec<usize>, String> {
+    let n = len / frames;
+    let shape = match *expected {
+        [rows, columns] if rows == frames as i64 && (columns < 0 || columns == n as i64) => {
+            vec![frames, n]
+        }
+        [_, _] => vec![1, len],
+        [_, _, _] => vec![1, frames, n],
+        [_, _, _, _] => vec![1, 1, frames, n],
+        _ => {
+            return Err(format!(
+                "model input of rank {} is not supported",
+                expected.len()
+            ));
+        }
+    };
+    for (axis, (&wanted, &given)) in expected.iter().zip(&shape).enumerate() {
+        if wanted >= 0 && wanted as usize != given {
+            return Err(format!(
+                "model input {expected:?} needs {wanted} values along axis {axis}, the features give {given}"
+            ));
+        }
+    }
+    Ok(shape)
+}
+
+/// Class probabilities from the outputs of a network: as they are if they already form a
+/// distribution, through a softmax otherwise. A single output is the probability of label 1,
+/// taken through a sigmoid unless it lies in `[0, 1]`.
+fn probabilities(outputs: &[f32]) -> Vec<f32> {
+    if let [output] = outputs {
+        let p = if (0.0..=1.0).contains(output) {
+            *output
+        } else {
+            1.0 / (1.0 + (-output).exp())
+        };
+        return vec![1.0 - p, p];
+    }
+    let sum = outputs.iter().sum::<f32>();
+    if outputs.iter().all(|p| (0.0..=1.0).contains(p)) && (sum - 1.0).abs() < 1e-3 {
+        return outputs.to_vec();
+    }
+    let max = outputs.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp = outputs.iter().map(|o| (o - max).exp()).collect::<Vec<_>>();
+    let sum = exp.iter().sum::<f32>();
+    exp.iter().map(|e| e / sum).collect()
+}
+
+/// Runs the detection model on the features of a frame, laid out in `shape` as
+/// `input_shape` gives it, and returns the predicted label together with the probability
+/// the model assigned to it.
+pub fn classify(session: &mut Session, values: Vec<f32>, shape: &[usize]) -> Option<(i64, f32)> {
+    let x = ArrayD::from_shape_vec(IxDyn(shape), values).unwrap();
+    let mut outputs = session
+        .run(inputs![TensorRef::from_array_view(x.view()).unwrap()])
+        .ok()?;
+    if !outputs.contains_key("output_label") {
+        // Neural networks output a score per class rather than a label and its probability.
+        let (_, scores) = outputs[0].try_extract_tensor::<f32>().ok()?;
+        let (label, probability) = probabilities(scores)
+            .into_iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        return Some((label as i64, probability));
+    }
+    let prob = outputs.remove("output_probability").unwrap();
+    let pred = outputs["output_label"]
+        .try_extract_tensor::<i64>()
+        .unwrap()
+        .1[0];
+    drop(outputs);
+    let prob: Sequence<DynMapValueType> = prob.into_dyn().downcast().unwrap();
+    let prob = prob.extract_sequence(session.allocator());
+    let prob = prob
+        .iter()
+        .map(|p| p.try_extract_map::<i64, f32>().unwrap())
+        .collect::<Vec<HashMap<i64, f32>>>();
+    let prob = *prob[0].get(&pred).unwrap();
+    Some((pred, prob))
+}
+
 // pub fn load_detection_model<P: AsRef<Path>>(
 //     model_path: P,
 // ) -> RandomForestClassifier<f32, i32, Array2<f32>, Vec<i32>> {
@@ -79,3 +467,96 @@ pub fn load_onnx<P: AsRef<Path>>(model_path: P) -> Session {
 //     ))
 //     .expect("Failed to deserialize location model")
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RMS in dBFS and its delta in the rows an extractor returns for `frames` frames of
+    /// silence with a loud frame at `loud`.
+    fn levels(deltas: usize, frames: usize, loud: usize) -> Vec<Option<(f32, f32)>> {
+        let config = FeatureConfig {
+            kind: Kind::Summary,
+            frame_len: 256,
+            hop: 256,
+            min_freq: 100.0,
+            deltas,
+            delta_width: 2,
+            ..FeatureConfig::default()
+        };
+        let (_, silence) = process_samples([0; 256].iter(), SAMPLE_RATE, &config);
+        let len = silence.len();
+        let mut extractor = Extractor::new(&config);
+        (0..frames)
+            .map(|i| {
+                let level = if i == loud { i32::MAX / 2 } else { 0 };
+                let frame = (0..256)
+                    .map(|n| if n % 2 == 0 { level } else { -level })
+                    .collect::<Vec<_>>();
+                let (_, values) = extractor.process(frame.iter(), SAMPLE_RATE)?;
+                Some((values[len - 1], values[2 * len - 1]))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stacks_only_real_frames() {
+        let config = FeatureConfig {
+            frame_len: 256,
+            hop: 256,
+            context_frames: 3,
+            ..FeatureConfig::default()
+        };
+        let mut extractor = Extractor::new(&config);
+        let frame = [0; 256];
+        assert!(extractor.process(frame.iter(), SAMPLE_RATE).is_none());
+        assert!(extractor.process(frame.iter(), SAMPLE_RATE).is_none());
+        let (freqs, values) = extractor.process(frame.iter(), SAMPLE_RATE).unwrap();
+        let (single, _) = process_samples(frame.iter(), SAMPLE_RATE, &config);
+        assert_eq!(freqs, single.repeat(3));
+        assert_eq!(freqs, frequencies(&config));
+        assert_eq!(values.len(), freqs.len());
+    }
+
+    #[test]
+    fn stacked_frames_fit_the_model_input() {
+        assert_eq!(shape_for(&[1, 12], 12, 3).unwrap(), [1, 12]);
+        assert_eq!(shape_for(&[-1, 12], 12, 3).unwrap(), [1, 12]);
+        assert_eq!(shape_for(&[3, 4], 12, 3).unwrap(), [3, 4]);
+        assert_eq!(shape_for(&[3, -1], 12, 3).unwrap(), [3, 4]);
+        assert_eq!(shape_for(&[1, 3, 4], 12, 3).unwrap(), [1, 3, 4]);
+        assert_eq!(shape_for(&[-1, 1, 3, 4], 12, 3).unwrap(), [1, 1, 3, 4]);
+        assert!(shape_for(&[3, 5], 12, 3).is_err());
+        assert!(shape_for(&[1, 10], 12, 3).is_err());
+        assert!(shape_for(&[12], 12, 3).is_err());
+    }
+
+    #[test]
+    fn network_outputs_become_probabilities() {
+        let softmax = probabilities(&[2.0, 0.0, -1.0]);
+        let expected = [0.8438, 0.1142, 0.0420];
+        for (p, e) in softmax.iter().zip(expected) {
+            assert!((p - e).abs() < 1e-4, "{softmax:?}");
+        }
+        assert_eq!(probabilities(&[0.25, 0.75]), [0.25, 0.75]);
+        assert_eq!(probabilities(&[0.75]), [0.25, 0.75]);
+        let sigmoid = probabilities(&[-2.0]);
+        assert!((sigmoid[1] - 0.1192).abs() < 1e-4 && (sigmoid[0] - 0.8808).abs() < 1e-4);
+    }
+
+    #[test]
+    fn deltas_are_centred_on_the_frame_they_are_returned_with() {
+        for deltas in [1, 2] {
+            let levels = levels(deltas, 16, 6);
+            // Nothing until every delta has its frames.
+            let first = levels.iter().position(Option::is_some).unwrap();
+            assert_eq!(first, deltas * 2 * 2);
+            // The loud frame is returned `deltas * delta_width` frames late, together with its
+            // delta, which is zero at the peak and rises before it.
+            let row = 6 + deltas * 2;
+            let [before, peak, after] = [row - 1, row, row + 1].map(|i| levels[i].unwrap());
+            assert!(peak.0 > -10.0 && before.0 < -100.0 && after.0 < -100.0);
+            assert!(peak.1.abs() < 1e-3 && before.1 > 1.0, "{levels:?}");
+        }
+    }
+}
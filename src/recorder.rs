@@ -0,0 +1,708 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hound::{SampleFormat, WavSpec};
+
+use crate::{
+    bwf::Metadata,
+    flac::FlacWriter,
+    ring::{Consumer, Producer, ring},
+    source::{AudioSource, CHUNK_FRAMES, Chunk, ChunkTiming, SourceError},
+    timestamp::Timestamp,
+    wav::WavWriter,
+};
+
+/// File format of recordings.
+#[derive(Debug, Clone, Copy)]
+pub enum Encoding {
+    Wav,
+    /// Lossless compression at `level`, from 0 (fastest) to `flac::MAX_LEVEL` (smallest).
+    /// Only integer samples can be encoded, 32-bit ones with their top 24 bits.
+    Flac {
+        level: u8,
+    },
+}
+
+impl Encoding {
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Wav => "wav",
+            Encoding::Flac { .. } => "flac",
+        }
+    }
+}
+
+/// How `record` writes files besides where to.
+pub struct Settings {
+    pub encoding: Encoding,
+    /// Seconds of audio between checkpoints, bounding how much a crash can lose.
+    pub checkpoint_seconds: f64,
+    /// Seconds of audio buffered between capture and the writer thread.
+    pub buffer_seconds: f64,
+}
+
+/// Where `record` writes and when it moves on to a new file.
+pub enum Output {
+    /// A single file written until the source ends.
+    File(PathBuf),
+    /// Consecutive files in `dir`, each finalized once it holds `max_seconds` of audio or
+    /// `max_bytes` bytes of uncompressed audio data, whichever comes first.
+    Segments {
+        dir: PathBuf,
+        /// File name without extension. `{date}` and `{time}` expand to the UTC start of the
+        /// segment, `{index}` to its 1-based position in the recording.
+        template: String,
+        max_seconds: Option<f64>,
+        max_bytes: Option<u64>,
+    },
+}
+
+impl Output {
+    fn frame_limit(&self, spec: WavSpec) -> Option<u64> {
+        let Output::Segments {
+            max_seconds,
+            max_bytes,
+            ..
+        } = self
+        else {
+            return None;
+        };
+        let frame_bytes = spec.channels as u64 * (spec.bits_per_sample as u64).div_ceil(8);
+        let by_time = max_seconds.map(|s| ((s * spec.sample_rate as f64) as u64).max(1));
+        let by_size = max_bytes.map(|b| (b / frame_bytes).max(1));
+        match (by_time, by_size) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Creates the file of the `index`th segment, starting at `start`. Segments never replace
+    /// existing files, nor take the name of an existing timestamps CSV, see `create_new`.
+    fn create_segment(
+        &self,
+        index: u32,
+        start: SystemTime,
+        encoding: Encoding,
+    ) -> io::Result<(PathBuf, File)> {
+        let (dir, template) = match self {
+            Output::File(path) => return Ok((path.clone(), File::create(path)?)),
+            Output::Segments { dir, template, .. } => (dir, template),
+        };
+        let start = Timestamp::from_system_time(start);
+        let name = template
+            .replace("{date}", &start.compact_date())
+            .replace("{time}", &start.compact_time())
+            .replace("{index}", &format!("{index:04}"));
+        create_new(dir, &name, encoding.extension(), &["csv"])
+    }
+}
+
+/// Creates `<stem>.<extension>` in `dir` without replacing an existing file: while the name
+/// is taken, `-2`, `-3` and so on are appended to `stem`. A name also counts as taken while
+/// a file of that stem with one of the `siblings` extensions exists, so the files written
+/// next to it can be created under the same stem.
+pub fn create_new(
+    dir: &Path,
+    stem: &str,
+    extension: &str,
+    siblings: &[&str],
+) -> io::Result<(PathBuf, File)> {
+    let mut attempt = 1;
+    loop {
+        let stem = match attempt {
+            1 => stem.to_owned(),
+            n => format!("{stem}-{n}"),
+        };
+        attempt += 1;
+        let path = dir.join(format!("{stem}.{extension}"));
+        if siblings.iter().any(|s| path.with_extension(s).exists()) {
+            continue;
+        }
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Device timestamps of the frames in one recording, written next to it as
+/// `<name>.csv` with lines `frame,time,silence,dropped`: the frame offset in the recording,
+/// its timestamp in seconds since the Unix epoch, 1 where silence replaces lost audio, and
+/// the number of frames dropped right before it because the write queue was full.
+struct Timestamps {
+    /// Frame of the recording's first frame in the captured stream.
+    start: u64,
+    end: u64,
+    csv: Option<BufWriter<File>>,
+    /// Where the CSV goes, named after the recording it belongs to.
+    path: PathBuf,
+    /// Whether an existing CSV may be replaced, as the recording's own file was when its
+    /// path was given explicitly.
+    replace: bool,
+}
+
+impl Timestamps {
+    fn add(&mut self, frame: u64, timing: ChunkTiming, dropped: u64) -> Result<(), SourceError> {
+        let csv = match self.csv.as_mut() {
+            Some(csv) => csv,
+            None => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .truncate(self.replace)
+                    .create(self.replace)
+                    .create_new(!self.replace)
+                    .open(&self.path)?;
+                let mut csv = BufWriter::new(file);
+                writeln!(csv, "frame,time,silence,dropped")?;
+                self.csv.insert(csv)
+            }
+        };
+        writeln!(
+            csv,
+            "{},{:.6},{},{dropped}",
+            frame - self.start,
+            timing.time,
+            timing.silence as u8
+        )?;
+        Ok(())
+    }
+}
+
+/// Writer for one recording in the chosen encoding.
+enum FileWriter {
+    Wav(WavWriter<BufWriter<File>>),
+    Flac(FlacWriter<BufWriter<File>>),
+}
+
+impl FileWriter {
+    fn new(
+        file: File,
+        spec: WavSpec,
+        metadata: &Metadata,
+        encoding: Encoding,
+    ) -> Result<Self, SourceError> {
+        let file = BufWriter::new(file);
+        Ok(match encoding {
+            Encoding::Wav => FileWriter::Wav(WavWriter::new(file, spec, Some(metadata))?),
+            Encoding::Flac { level } => {
+                FileWriter::Flac(FlacWriter::new(file, spec, Some(metadata), level)?)
+            }
+        })
+    }
+
+    fn write(&mut self, chunk: &Chunk) -> Result<(), SourceError> {
+        match (self, chunk) {
+            (FileWriter::Wav(writer), Chunk::Int(samples)) => writer.write_int(samples)?,
+            (FileWriter::Wav(writer), Chunk::Float(samples)) => writer.write_float(samples)?,
+            (FileWriter::Flac(writer), Chunk::Int(samples)) => writer.write_int(samples)?,
+            (FileWriter::Flac(_), Chunk::Float(_)) => {
+                return Err(SourceError::Unsupported("float samples in FLAC".to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<(), SourceError> {
+        match self {
+            FileWriter::Wav(writer) => writer.checkpoint()?,
+            FileWriter::Flac(writer) => writer.checkpoint()?,
+        }
+        Ok(())
+    }
+
+    fn finalize(self) -> Result<(), SourceError> {
+        match self {
+            FileWriter::Wav(writer) => writer.finalize()?,
+            FileWriter::Flac(writer) => writer.finalize()?,
+        }
+        Ok(())
+    }
+}
+
+struct Segment {
+    writer: FileWriter,
+    path: PathBuf,
+    frames: u64,
+    checkpointed_frames: u64,
+    timestamps: Timestamps,
+}
+
+impl Segment {
+    fn create(
+        path: PathBuf,
+        file: File,
+        spec: WavSpec,
+        start: u64,
+        metadata: &Metadata,
+        encoding: Encoding,
+        replace: bool,
+    ) -> Result<Self, SourceError> {
+        let writer = FileWriter::new(file, spec, metadata, encoding)?;
+        println!("recording to {}", path.display());
+        Ok(Self {
+            writer,
+            timestamps: Timestamps {
+                start,
+                end: u64::MAX,
+                csv: None,
+                path: path.with_extension("csv"),
+                replace,
+            },
+            path,
+            frames: 0,
+            checkpointed_frames: 0,
+        })
+    }
+
+    /// Writes the frames `start..end` of `chunk`.
+    fn write(
+        &mut self,
+        chunk: &Chunk,
+        channels: usize,
+        start: usize,
+        end: usize,
+    ) -> Result<(), SourceError> {
+        let range = start * channels..end * channels;
+        match chunk {
+            Chunk::Int(samples) => self.writer.write(&Chunk::Int(&samples[range]))?,
+            Chunk::Float(samples) => self.writer.write(&Chunk::Float(&samples[range]))?,
+        }
+        self.frames += (end - start) as u64;
+        Ok(())
+    }
+
+    /// Updates the header and syncs the file once `interval` frames were written since the
+    /// last time, so a crash loses at most that much audio.
+    fn checkpoint_every(&mut self, interval: u64) -> Result<(), SourceError> {
+        if self.frames - self.checkpointed_frames >= interval {
+            if let Some(csv) = self.timestamps.csv.as_mut() {
+                csv.flush()?;
+            }
+            self.writer.checkpoint()?;
+            self.checkpointed_frames = self.frames;
+        }
+        Ok(())
+    }
+
+    /// Finalizes the file and returns its timestamps, which may still receive entries
+    /// that arrive late.
+    fn finalize(mut self, sample_rate: u32) -> Result<Timestamps, SourceError> {
+        self.writer.finalize()?;
+        if let Some(csv) = self.timestamps.csv.as_mut() {
+            csv.flush()?;
+        }
+        println!(
+            "finished {} ({:.1} s)",
+            self.path.display(),
+            self.frames as f64 / sample_rate as f64
+        );
+        self.timestamps.end = self.timestamps.start + self.frames;
+        Ok(self.timestamps)
+    }
+}
+
+/// Counters describing how well capture kept up, updated while `record` runs.
+#[derive(Default)]
+pub struct CaptureStats {
+    /// Frames read from the source.
+    pub frames: AtomicU64,
+    /// Frames read but discarded because the write queue was full.
+    pub dropped_frames: AtomicU64,
+    /// Overruns the source itself reported, each losing an unknown amount of audio.
+    pub xruns: AtomicU64,
+}
+
+/// Sample types `record` can queue between its threads.
+trait Sample: Copy + Default + Send {
+    fn samples<'a>(chunk: &Chunk<'a>) -> Option<&'a [Self]>;
+    fn chunk(samples: &[Self]) -> Chunk<'_>;
+}
+
+impl Sample for i32 {
+    fn samples<'a>(chunk: &Chunk<'a>) -> Option<&'a [Self]> {
+        match chunk {
+            Chunk::Int(samples) => Some(samples),
+            Chunk::Float(_) => None,
+        }
+    }
+
+    fn chunk(samples: &[Self]) -> Chunk<'_> {
+        Chunk::Int(samples)
+    }
+}
+
+impl Sample for f32 {
+    fn samples<'a>(chunk: &Chunk<'a>) -> Option<&'a [Self]> {
+        match chunk {
+            Chunk::Float(samples) => Some(samples),
+            Chunk::Int(_) => None,
+        }
+    }
+
+    fn chunk(samples: &[Self]) -> Chunk<'_> {
+        Chunk::Float(samples)
+    }
+}
+
+/// Splits chunks into files according to `Output`.
+struct Writer<'a> {
+    output: &'a Output,
+    spec: WavSpec,
+    encoding: Encoding,
+    metadata: Metadata,
+    /// Whether the source is live, passing a timestamp for the first frames right away.
+    live: bool,
+    limit: Option<u64>,
+    checkpoint_frames: u64,
+    segment: Option<Segment>,
+    /// Timestamps of the previous segment, for entries that arrive after it was finished.
+    previous: Option<Timestamps>,
+    index: u32,
+    /// Frames written so far over all segments.
+    written: u64,
+    /// Timestamps of queued frames with the number of frames dropped right before them.
+    timings: Receiver<(u64, ChunkTiming, u64)>,
+    /// Timestamps received for frames that were not written yet.
+    pending: VecDeque<(u64, ChunkTiming, u64)>,
+    /// The latest timestamp taken from `pending`.
+    last_timing: Option<(u64, ChunkTiming, u64)>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(
+        output: &'a Output,
+        spec: WavSpec,
+        settings: &Settings,
+        metadata: &Metadata,
+        live: bool,
+        timings: Receiver<(u64, ChunkTiming, u64)>,
+    ) -> Result<Self, SourceError> {
+        if let (Encoding::Flac { .. }, SampleFormat::Float) =
+            (settings.encoding, spec.sample_format)
+        {
+            return Err(SourceError::Unsupported(
+                "float samples in FLAC, record them as WAV".to_owned(),
+            ));
+        }
+        if let Output::Segments { dir, .. } = output {
+            fs::create_dir_all(dir)?;
+        }
+        let mut metadata = metadata.clone();
+        if metadata.channel_names.is_empty() {
+            metadata.channel_names = (1..=spec.channels).map(|c| c.to_string()).collect();
+        }
+        Ok(Self {
+            output,
+            spec,
+            encoding: settings.encoding,
+            metadata,
+            live,
+            limit: output.frame_limit(spec),
+            checkpoint_frames: ((settings.checkpoint_seconds * spec.sample_rate as f64) as u64)
+                .max(1),
+            segment: None,
+            previous: None,
+            index: 0,
+            written: 0,
+            timings,
+            pending: VecDeque::new(),
+            last_timing: None,
+        })
+    }
+
+    /// Wall clock time of the next frame to write, extrapolated from the device timestamp
+    /// nearest before it or, failing that, after it. Live sources pass the timestamp of the
+    /// first frames right after queueing them, so the first segment waits for it briefly.
+    /// Sources without timestamps start at the current time.
+    fn start_time(&mut self) -> SystemTime {
+        self.pending.extend(self.timings.try_iter());
+        if self.live
+            && self.index == 1
+            && self.pending.is_empty()
+            && let Ok(timing) = self.timings.recv_timeout(Duration::from_secs(1))
+        {
+            self.pending.push_back(timing);
+        }
+        let frame = self.written;
+        let nearest = self
+            .pending
+            .iter()
+            .rev()
+            .find(|(f, ..)| *f <= frame)
+            .or(self.last_timing.as_ref())
+            .or(self.pending.front());
+        match nearest {
+            Some((f, timing, _)) => {
+                let offset = (frame as f64 - *f as f64) / self.spec.sample_rate as f64;
+                UNIX_EPOCH + Duration::from_secs_f64((timing.time + offset).max(0.0))
+            }
+            None => SystemTime::now(),
+        }
+    }
+
+    fn write(&mut self, chunk: &Chunk) -> Result<(), SourceError> {
+        let channels = self.spec.channels as usize;
+        let frames = chunk.len() / channels;
+        let mut offset = 0;
+        while offset < frames {
+            let current = match self.segment.as_mut() {
+                Some(current) => current,
+                None => {
+                    self.index += 1;
+                    let start = self.start_time();
+                    let (path, file) =
+                        self.output
+                            .create_segment(self.index, start, self.encoding)?;
+                    let metadata = self.metadata.starting_at(start, self.spec.sample_rate);
+                    self.segment.insert(Segment::create(
+                        path,
+                        file,
+                        self.spec,
+                        self.written,
+                        &metadata,
+                        self.encoding,
+                        matches!(self.output, Output::File(_)),
+                    )?)
+                }
+            };
+            let end = match self.limit {
+                Some(limit) => frames.min(offset + (limit - current.frames) as usize),
+                None => frames,
+            };
+            current.write(chunk, channels, offset, end)?;
+            self.written += (end - offset) as u64;
+            offset = end;
+            if Some(current.frames) == self.limit {
+                self.previous = Some(
+                    self.segment
+                        .take()
+                        .unwrap()
+                        .finalize(self.spec.sample_rate)?,
+                );
+            } else {
+                current.checkpoint_every(self.checkpoint_frames)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the timestamps received so far to the segments their frames were written to.
+    fn log_timings(&mut self) -> Result<(), SourceError> {
+        self.pending.extend(self.timings.try_iter());
+        while let Some((frame, timing, dropped)) = self.pending.pop_front() {
+            if frame >= self.written {
+                self.pending.push_front((frame, timing, dropped));
+                break;
+            }
+            self.last_timing = Some((frame, timing, dropped));
+            let timestamps = [
+                self.segment.as_mut().map(|s| &mut s.timestamps),
+                self.previous.as_mut(),
+            ]
+            .into_iter()
+            .flatten()
+            .find(|t| (t.start..t.end).contains(&frame));
+            if let Some(timestamps) = timestamps {
+                timestamps.add(frame, timing, dropped)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), SourceError> {
+        self.log_timings()?;
+        if let Some(current) = self.segment {
+            current.finalize(self.spec.sample_rate)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes everything `source` produces to WAV or FLAC files until it is exhausted or
+/// `running` is cleared. Every file is finalized before the next one is started, and its
+/// header is kept valid every `checkpoint_seconds` in between.
+///
+/// Files are written on a separate thread fed through a ring holding `buffer_seconds` of
+/// audio, so slow storage does not stall capture. When the ring is full, live sources drop
+/// the frames that do not fit while other sources wait.
+pub fn record(
+    source: &mut dyn AudioSource,
+    output: &Output,
+    metadata: &Metadata,
+    settings: &Settings,
+    running: &AtomicBool,
+    stats: &CaptureStats,
+) -> Result<(), SourceError> {
+    let spec = source.spec();
+    let (timings, received) = mpsc::channel();
+    let buffer_seconds = settings.buffer_seconds;
+    let writer = Writer::new(output, spec, settings, metadata, source.is_live(), received)?;
+    match spec.sample_format {
+        SampleFormat::Int => {
+            record_as::<i32>(source, writer, timings, buffer_seconds, running, stats)
+        }
+        SampleFormat::Float => {
+            record_as::<f32>(source, writer, timings, buffer_seconds, running, stats)
+        }
+    }
+}
+
+fn record_as<T: Sample>(
+    source: &mut dyn AudioSource,
+    writer: Writer,
+    timings: Sender<(u64, ChunkTiming, u64)>,
+    buffer_seconds: f64,
+    running: &AtomicBool,
+    stats: &CaptureStats,
+) -> Result<(), SourceError> {
+    let spec = source.spec();
+    let channels = spec.channels as usize;
+    let capacity =
+        ((buffer_seconds * spec.sample_rate as f64) as usize).max(CHUNK_FRAMES) * channels;
+    let (producer, consumer) = ring::<T>(capacity);
+
+    thread::scope(|s| {
+        let writing = thread::Builder::new()
+            .name("writer".to_owned())
+            .spawn_scoped(s, move || write_queued(consumer, writer, channels))
+            .unwrap();
+        let captured = capture(source, producer, timings, running, stats);
+        update_xruns(source, stats);
+        let written = writing.join().unwrap();
+        captured.and(written)
+    })
+}
+
+/// Reads `source` into the ring until it is exhausted, `running` is cleared or the writer
+/// has stopped. Device timestamps are passed on about once a second, for every chunk of
+/// silence standing in for lost audio and for the first chunk queued after frames were
+/// dropped, tagged with the position of the chunk in the ring and the number dropped.
+fn capture<T: Sample>(
+    source: &mut dyn AudioSource,
+    mut producer: Producer<T>,
+    timings: Sender<(u64, ChunkTiming, u64)>,
+    running: &AtomicBool,
+    stats: &CaptureStats,
+) -> Result<(), SourceError> {
+    let spec = source.spec();
+    let channels = spec.channels as usize;
+    let live = source.is_live();
+    let mut queued = 0;
+    let mut timed = None;
+    // Frames dropped since the last timestamp was passed on.
+    let mut dropped = 0;
+    println!("start audio read");
+    while running.load(Ordering::Relaxed) && !producer.is_closed() {
+        let Some(chunk) = source.read()? else {
+            break;
+        };
+        let samples = T::samples(&chunk).ok_or_else(|| {
+            SourceError::Unsupported("chunk does not match the source format".to_owned())
+        })?;
+        let frames = (samples.len() / channels) as u64;
+        stats.frames.fetch_add(frames, Ordering::Relaxed);
+        let frame = queued;
+        let pushed = queue(&mut producer, samples, channels, live, stats);
+        queued += pushed;
+        if pushed > 0
+            && let Some(timing) = source.timing()
+            && (timing.silence
+                || dropped > 0
+                || timed.is_none_or(|t| frame - t >= spec.sample_rate as u64))
+        {
+            timed = Some(frame);
+            let _ = timings.send((frame, timing, dropped));
+            dropped = 0;
+        }
+        dropped += frames - pushed;
+        update_xruns(source, stats);
+    }
+    Ok(())
+}
+
+/// Copies the overruns `source` reported so far into `stats`, announcing new ones.
+fn update_xruns(source: &dyn AudioSource, stats: &CaptureStats) {
+    let xruns = source.xruns();
+    if xruns != stats.xruns.swap(xruns, Ordering::Relaxed) {
+        println!("Capture overrun, {xruns} so far");
+    }
+}
+
+/// Pushes the whole frames of `samples` into the ring, waiting for room unless `live`.
+/// Returns the number of frames pushed.
+fn queue<T: Sample>(
+    producer: &mut Producer<T>,
+    samples: &[T],
+    channels: usize,
+    live: bool,
+    stats: &CaptureStats,
+) -> u64 {
+    let mut rest = samples;
+    loop {
+        let fit = rest.len().min(producer.free() / channels * channels);
+        rest = &rest[producer.push(&rest[..fit])..];
+        let pushed = ((samples.len() - rest.len()) / channels) as u64;
+        if rest.is_empty() || producer.is_closed() {
+            return pushed;
+        }
+        if live {
+            let dropped = (rest.len() / channels) as u64;
+            let total = stats.dropped_frames.fetch_add(dropped, Ordering::Relaxed) + dropped;
+            println!("Write queue full, {total} frames dropped so far");
+            return pushed;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Writes samples from the ring until the capture side is done and the ring is drained.
+fn write_queued<T: Sample>(
+    mut consumer: Consumer<T>,
+    mut writer: Writer,
+    channels: usize,
+) -> Result<(), SourceError> {
+    let mut buf = vec![T::default(); CHUNK_FRAMES * channels];
+    loop {
+        let closed = consumer.is_closed();
+        let n = consumer.pop(&mut buf);
+        if n > 0 {
+            writer.write(&T::chunk(&buf[..n]))?;
+            writer.log_timings()?;
+        } else if closed {
+            return writer.finish();
+        } else {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_files_skip_names_taken_by_them_or_their_siblings() {
+        let dir = std::env::temp_dir().join(format!("recorder-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("rec.wav"), b"old").unwrap();
+        fs::write(dir.join("rec-2.csv"), b"old").unwrap();
+
+        let (path, _) = create_new(&dir, "rec", "wav", &["csv"]).unwrap();
+        assert_eq!(path, dir.join("rec-3.wav"));
+        let (path, _) = create_new(&dir, "rec", "wav", &[]).unwrap();
+        assert_eq!(path, dir.join("rec-2.wav"));
+        assert_eq!(fs::read(dir.join("rec.wav")).unwrap(), b"old");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
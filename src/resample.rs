@@ -0,0 +1,306 @@
+//! Sample rate conversion by band-limited interpolation: every output sample is the input
+//! convolved with a Kaiser-windowed sinc centred on its position, low-passed below the lower
+//! of the two Nyquist frequencies.
+
+use std::f64::consts::PI;
+
+use hound::{SampleFormat, WavSpec};
+
+use crate::{
+    bwf::Metadata,
+    source::{AudioSource, CHUNK_FRAMES, Chunk, SourceError},
+};
+
+/// Zero crossings of the sinc on each side of the centre, at the output rate when
+/// downsampling.
+const ZERO_CROSSINGS: usize = 16;
+/// Kernel table entries per zero crossing.
+const RESOLUTION: usize = 512;
+/// Kaiser window shape, about 80 dB of stopband attenuation.
+const BETA: f64 = 8.0;
+/// Passband edge relative to the Nyquist frequency, leaving room for the transition band.
+const ROLLOFF: f64 = 0.95;
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Zeroth-order modified Bessel function of the first kind.
+pub(crate) fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+/// Windowed sinc sampled at `1 / RESOLUTION` steps from 0 to `ZERO_CROSSINGS`, with one
+/// extra zero entry so interpolation can look one step ahead.
+fn kernel() -> Vec<f32> {
+    let len = ZERO_CROSSINGS * RESOLUTION;
+    let norm = bessel_i0(BETA);
+    let mut table = (0..=len)
+        .map(|i| {
+            let x = i as f64 / RESOLUTION as f64;
+            let sinc = if i == 0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            let w = x / ZERO_CROSSINGS as f64;
+            let window = bessel_i0(BETA * (1.0 - w * w).max(0.0).sqrt()) / norm;
+            (sinc * window) as f32
+        })
+        .collect::<Vec<_>>();
+    table.push(0.0);
+    table
+}
+
+/// Converts another source to `rate`, producing float samples in `[-1, 1]`.
+pub struct Resampler {
+    inner: Box<dyn AudioSource + Send>,
+    spec: WavSpec,
+    /// Output rate over input rate, reduced: `up` output frames per `down` input frames.
+    up: u64,
+    down: u64,
+    kernel: Vec<f32>,
+    /// Cutoff relative to the input Nyquist frequency.
+    cutoff: f64,
+    /// Input frames each side of an output position that contribute to it.
+    half_width: u64,
+    /// Interleaved input history, converted to floats.
+    input: Vec<f32>,
+    /// Input frame index of the first frame in `input`.
+    input_start: u64,
+    /// Whether `inner` is exhausted, after which the input is padded with zeros.
+    ended: bool,
+    /// Output frames produced so far.
+    position: u64,
+    out: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(inner: Box<dyn AudioSource + Send>, rate: u32) -> Self {
+        let in_spec = inner.spec();
+        let divisor = gcd(rate as u64, in_spec.sample_rate as u64);
+        let up = rate as u64 / divisor;
+        let down = in_spec.sample_rate as u64 / divisor;
+        let cutoff = (up as f64 / down as f64).min(1.0) * ROLLOFF;
+        Self {
+            inner,
+            spec: WavSpec {
+                sample_rate: rate,
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+                ..in_spec
+            },
+            up,
+            down,
+            kernel: kernel(),
+            cutoff,
+            half_width: (ZERO_CROSSINGS as f64 / cutoff).ceil() as u64,
+            input: Vec::new(),
+            input_start: 0,
+            ended: false,
+            position: 0,
+            out: Vec::new(),
+        }
+    }
+
+    /// Appends the next chunk of `inner` to the history. Returns false at the end of input.
+    fn fill(&mut self) -> Result<bool, SourceError> {
+        let scale = match self.inner.spec().sample_format {
+            SampleFormat::Int => 1.0 / (1u64 << (self.inner.spec().bits_per_sample - 1)) as f32,
+            SampleFormat::Float => 1.0,
+        };
+        match self.inner.read()? {
+            Some(Chunk::Int(samples)) => {
+                self.input.extend(samples.iter().map(|s| *s as f32 * scale));
+            }
+            Some(Chunk::Float(samples)) => self.input.extend_from_slice(samples),
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Kernel value `distance` input frames away from an output position.
+    fn tap(&self, distance: f64) -> f32 {
+        let x = (distance * self.cutoff).abs() * RESOLUTION as f64;
+        let i = x as usize;
+        if i >= ZERO_CROSSINGS * RESOLUTION {
+            return 0.0;
+        }
+        let frac = (x - i as f64) as f32;
+        self.kernel[i] + (self.kernel[i + 1] - self.kernel[i]) * frac
+    }
+}
+
+impl AudioSource for Resampler {
+    fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    fn is_live(&self) -> bool {
+        self.inner.is_live()
+    }
+
+    fn xruns(&self) -> u64 {
+        self.inner.xruns()
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        self.inner.metadata()
+    }
+
+    fn read(&mut self) -> Result<Option<Chunk<'_>>, SourceError> {
+        let channels = self.spec.channels as usize;
+        self.out.clear();
+        while self.out.len() < CHUNK_FRAMES * channels {
+            // Input position of the next output frame, as a whole frame and a fraction.
+            let numerator = self.position * self.down;
+            let centre = numerator / self.up;
+            let frac = (numerator % self.up) as f64 / self.up as f64;
+            let buffered = self.input_start + (self.input.len() / channels) as u64;
+            if centre + self.half_width >= buffered && !self.ended {
+                if !self.fill()? {
+                    self.ended = true;
+                }
+                continue;
+            }
+            if self.ended && centre >= buffered {
+                break;
+            }
+
+            let first = centre
+                .saturating_sub(self.half_width - 1)
+                .max(self.input_start);
+            let last = (centre + self.half_width).min(buffered - 1);
+            let frame = self.out.len();
+            self.out.resize(frame + channels, 0.0);
+            for k in first..=last {
+                let weight = self.tap(k as f64 - centre as f64 - frac);
+                let at = (k - self.input_start) as usize * channels;
+                for (out, sample) in self.out[frame..].iter_mut().zip(&self.input[at..]) {
+                    *out += weight * sample;
+                }
+            }
+            for out in &mut self.out[frame..] {
+                *out = (*out * self.cutoff as f32).clamp(-1.0, 1.0);
+            }
+            self.position += 1;
+
+            // Drop input no later output frame reaches back to.
+            let keep_from = centre.saturating_sub(self.half_width);
+            if keep_from > self.input_start + CHUNK_FRAMES as u64 {
+                let drop = (keep_from - self.input_start) as usize;
+                self.input.drain(..drop * channels);
+                self.input_start = keep_from;
+            }
+        }
+        if self.out.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Chunk::Float(&self.out)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use alsa::pcm::Format;
+
+    use super::*;
+    use crate::source::RawSource;
+
+    /// Resamples `samples`, interleaved float frames of `channels` at `from` Hz, to `to` Hz.
+    fn resample(samples: &[f32], channels: u16, from: u32, to: u32) -> Vec<f32> {
+        let bytes = samples
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect::<Vec<_>>();
+        let raw = RawSource::new(Cursor::new(bytes), Format::FloatLE, channels, from).unwrap();
+        let mut resampler = Resampler::new(Box::new(raw), to);
+        assert_eq!(resampler.spec().sample_rate, to);
+        let mut out = Vec::new();
+        while let Some(chunk) = resampler.read().unwrap() {
+            match chunk {
+                Chunk::Float(samples) => out.extend_from_slice(samples),
+                Chunk::Int(_) => panic!("resampler returned integers"),
+            }
+        }
+        out
+    }
+
+    fn tone(hz: f64, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (0.5 * (2.0 * PI * hz * i as f64 / rate as f64).sin()) as f32)
+            .collect()
+    }
+
+    /// Largest difference from `expected` away from the edges, where the filter runs into
+    /// the zero padding.
+    fn max_error(out: &[f32], expected: &[f32]) -> f32 {
+        let edge = 100;
+        out[edge..out.len() - edge]
+            .iter()
+            .zip(&expected[edge..])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn output_length_follows_the_rate_ratio() {
+        assert_eq!(resample(&vec![0.0; 48000], 1, 48000, 16000).len(), 16000);
+        assert_eq!(resample(&vec![0.0; 44100], 1, 44100, 48000).len(), 48000);
+        assert_eq!(
+            resample(&vec![0.0; 2 * 4800], 2, 48000, 8000).len(),
+            2 * 800
+        );
+    }
+
+    #[test]
+    fn passes_tones_below_the_cutoff() {
+        for (from, to) in [(48000, 16000), (44100, 48000), (16000, 16000)] {
+            let out = resample(&tone(1000.0, from, from as usize), 1, from, to);
+            let expected = tone(1000.0, to, to as usize);
+            let error = max_error(&out, &expected);
+            assert!(error < 0.005, "{from} -> {to} Hz: error {error}");
+        }
+    }
+
+    #[test]
+    fn removes_tones_above_the_new_nyquist_frequency() {
+        let out = resample(&tone(10000.0, 48000, 48000), 1, 48000, 16000);
+        let error = max_error(&out, &[0.0; 16000]);
+        assert!(error < 0.005, "alias of {error}");
+    }
+
+    #[test]
+    fn keeps_channels_apart() {
+        let left = tone(1000.0, 48000, 4800);
+        let stereo = left.iter().flat_map(|s| [*s, 0.0]).collect::<Vec<_>>();
+        let out = resample(&stereo, 2, 48000, 16000);
+        let right = out.iter().skip(1).step_by(2).copied().collect::<Vec<_>>();
+        assert_eq!(max_error(&right, &[0.0; 1600]), 0.0);
+        let left = out.iter().step_by(2).copied().collect::<Vec<_>>();
+        assert!(max_error(&left, &tone(1000.0, 16000, 1600)) < 0.005);
+    }
+
+    #[test]
+    fn scales_integer_input_to_unit_range() {
+        let bytes = [0x00u8, 0x40].repeat(4800);
+        let raw = RawSource::new(Cursor::new(bytes), Format::S16LE, 1, 48000).unwrap();
+        let mut resampler = Resampler::new(Box::new(raw), 16000);
+        let Some(Chunk::Float(out)) = resampler.read().unwrap() else {
+            panic!("expected float samples");
+        };
+        // A constant 0.5 shows through the filter's passband ripple.
+        assert!((out[800] - 0.5).abs() < 0.005, "{}", out[800]);
+    }
+}
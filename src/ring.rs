@@ -0,0 +1,245 @@
+//! Preallocated single-producer single-consumer ring buffer for handing samples from the
+//! capture thread to a slower consumer without locks or allocations.
+
+use std::{
+    cell::UnsafeCell,
+    ptr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+struct Shared<T> {
+    buf: Box<[UnsafeCell<T>]>,
+    /// Total number of items written. Only the producer stores it.
+    head: AtomicUsize,
+    /// Total number of items read. Only the consumer stores it.
+    tail: AtomicUsize,
+    /// Set once either side is dropped.
+    closed: AtomicBool,
+}
+
+// The producer only writes the slots between `head` and `tail + capacity`, the consumer only
+// reads those between `tail` and `head`, and each publishes its progress with release stores.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn ptr(&self) -> *mut T {
+        // `UnsafeCell<T>` has the same layout as `T`.
+        self.buf.as_ptr() as *mut T
+    }
+}
+
+/// Creates a ring holding up to `capacity` items.
+pub fn ring<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let shared = Arc::new(Shared {
+        buf: (0..capacity)
+            .map(|_| UnsafeCell::new(T::default()))
+            .collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Producer<T> {
+    /// Number of items that can be pushed without overwriting unread ones.
+    pub fn free(&self) -> usize {
+        let head = self.shared.head.load(Ordering::Relaxed);
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        self.shared.buf.len() - head.wrapping_sub(tail)
+    }
+
+    /// Copies as many of `items` as fit and returns how many were copied. All of them become
+    /// visible to the consumer at once.
+    pub fn push(&mut self, items: &[T]) -> usize {
+        let capacity = self.shared.buf.len();
+        let head = self.shared.head.load(Ordering::Relaxed);
+        let n = items.len().min(self.free());
+        let start = head % capacity;
+        let first = n.min(capacity - start);
+        // SAFETY: the `n` slots after `head` are free, see `Shared`.
+        unsafe {
+            let buf = self.shared.ptr();
+            ptr::copy_nonoverlapping(items.as_ptr(), buf.add(start), first);
+            ptr::copy_nonoverlapping(items.as_ptr().add(first), buf, n - first);
+        }
+        self.shared
+            .head
+            .store(head.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    /// Whether the consumer is gone.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Consumer<T> {
+    /// Moves up to `out.len()` items into `out` and returns how many were moved.
+    pub fn pop(&mut self, out: &mut [T]) -> usize {
+        let capacity = self.shared.buf.len();
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let head = self.shared.head.load(Ordering::Acquire);
+        let n = out.len().min(head.wrapping_sub(tail));
+        let start = tail % capacity;
+        let first = n.min(capacity - start);
+        // SAFETY: the `n` slots after `tail` were published by the producer, see `Shared`.
+        unsafe {
+            let buf = self.shared.ptr();
+            ptr::copy_nonoverlapping(buf.add(start), out.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(buf, out.as_mut_ptr().add(first), n - first);
+        }
+        self.shared
+            .tail
+            .store(tail.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    /// Whether the producer is gone. Items pushed before that can still be popped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn wraps_around_the_end_of_the_buffer() {
+        let (mut producer, mut consumer) = ring::<u32>(5);
+        let mut out = [0; 5];
+        assert_eq!(producer.push(&[1, 2, 3, 4]), 4);
+        assert_eq!(consumer.pop(&mut out[..3]), 3);
+        assert_eq!(out[..3], [1, 2, 3]);
+        // Two slots before the end, two after.
+        assert_eq!(producer.push(&[5, 6, 7, 8]), 4);
+        assert_eq!(consumer.pop(&mut out), 5);
+        assert_eq!(out, [4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn pushes_only_what_fits() {
+        let (mut producer, mut consumer) = ring::<u32>(4);
+        assert_eq!(producer.push(&[1, 2, 3]), 3);
+        assert_eq!(producer.push(&[4, 5, 6]), 1);
+        assert_eq!(producer.push(&[7]), 0);
+        let mut out = [0; 8];
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(out[..4], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn pop_on_empty_moves_nothing() {
+        let (mut producer, mut consumer) = ring::<u32>(4);
+        let mut out = [9; 4];
+        assert_eq!(consumer.pop(&mut out), 0);
+        assert_eq!(out, [9; 4]);
+        producer.push(&[1, 2]);
+        assert_eq!(consumer.pop(&mut out), 2);
+        assert_eq!(consumer.pop(&mut out), 0);
+    }
+
+    #[test]
+    fn drains_after_the_producer_is_gone() {
+        let (mut producer, mut consumer) = ring::<u32>(4);
+        producer.push(&[1, 2, 3]);
+        assert!(!consumer.is_closed());
+        drop(producer);
+        assert!(consumer.is_closed());
+        let mut out = [0; 4];
+        assert_eq!(consumer.pop(&mut out), 3);
+        assert_eq!(out[..3], [1, 2, 3]);
+        assert_eq!(consumer.pop(&mut out), 0);
+    }
+
+    #[test]
+    fn producer_sees_the_consumer_go() {
+        let (producer, consumer) = ring::<u32>(4);
+        assert!(!producer.is_closed());
+        drop(consumer);
+        assert!(producer.is_closed());
+    }
+
+    #[test]
+    fn free_counts_unread_items() {
+        let (mut producer, mut consumer) = ring::<u32>(4);
+        assert_eq!(producer.free(), 4);
+        producer.push(&[1, 2, 3]);
+        assert_eq!(producer.free(), 1);
+        consumer.pop(&mut [0; 2]);
+        assert_eq!(producer.free(), 3);
+        producer.push(&[4, 5, 6]);
+        assert_eq!(producer.free(), 0);
+        consumer.pop(&mut [0; 4]);
+        assert_eq!(producer.free(), 4);
+    }
+
+    #[test]
+    fn sequence_arrives_intact_across_threads() {
+        const COUNT: u32 = 200_000;
+        let (mut producer, mut consumer) = ring::<u32>(64);
+        let sender = thread::spawn(move || {
+            let mut next = 0;
+            while next < COUNT {
+                let chunk = (next..(next + 37).min(COUNT)).collect::<Vec<_>>();
+                let mut sent = 0;
+                while sent < chunk.len() {
+                    sent += producer.push(&chunk[sent..]);
+                    thread::yield_now();
+                }
+                next += chunk.len() as u32;
+            }
+        });
+
+        let mut expected = 0;
+        let mut out = [0; 23];
+        loop {
+            let closed = consumer.is_closed();
+            let n = consumer.pop(&mut out);
+            for &item in &out[..n] {
+                assert_eq!(item, expected);
+                expected += 1;
+            }
+            if n == 0 {
+                if closed {
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+        sender.join().unwrap();
+        assert_eq!(expected, COUNT);
+    }
+}
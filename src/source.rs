@@ -0,0 +1,681 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use alsa::pcm::Format;
+use hound::{SampleFormat, WavSpec};
+
+use crate::{bwf::Metadata, flac::FlacSource, wav};
+
+/// Number of frames a source returns per `read` at most.
+pub const CHUNK_FRAMES: usize = 1024 * 16;
+
+/// A chunk of interleaved samples in the representation described by the source's spec.
+pub enum Chunk<'a> {
+    Int(&'a [i32]),
+    Float(&'a [f32]),
+}
+
+impl Chunk<'_> {
+    pub fn len(&self) -> usize {
+        match self {
+            Chunk::Int(samples) => samples.len(),
+            Chunk::Float(samples) => samples.len(),
+        }
+    }
+}
+
+/// When the chunk returned by the latest `read` was captured.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkTiming {
+    /// Device timestamp of the first frame in seconds since the Unix epoch.
+    pub time: f64,
+    /// Whether the chunk is silence standing in for audio lost in an overrun.
+    pub silence: bool,
+}
+
+/// Converts a float sample in `[-1, 1]` to a full-scale 32-bit integer.
+pub fn float_to_i32(sample: f32) -> i32 {
+    (sample.clamp(-1.0, 1.0) as f64 * i32::MAX as f64) as i32
+}
+
+pub trait AudioSource {
+    /// Layout of the samples returned by `read`. Integer samples use the full range of
+    /// `bits_per_sample`, float samples are in `[-1, 1]`.
+    fn spec(&self) -> WavSpec;
+
+    /// Whether the source produces samples in real time and loses them when they are not
+    /// read quickly enough.
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    /// Number of overruns so far, i.e. times a live source lost samples because they were not
+    /// read in time.
+    fn xruns(&self) -> u64 {
+        0
+    }
+
+    /// How the audio was recorded, for sources that carry such information.
+    fn metadata(&self) -> Option<&Metadata> {
+        None
+    }
+
+    /// Timing of the chunk returned by the latest `read`, for sources that know it.
+    fn timing(&self) -> Option<ChunkTiming> {
+        None
+    }
+
+    /// Reads the next chunk of interleaved samples. Returns `None` once the source is
+    /// exhausted.
+    fn read(&mut self) -> Result<Option<Chunk<'_>>, SourceError>;
+}
+
+#[derive(Debug)]
+pub enum SourceError {
+    Alsa(alsa::Error),
+    Io(io::Error),
+    Unsupported(String),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Alsa(err) => write!(f, "ALSA: {err}"),
+            SourceError::Io(err) => write!(f, "IO: {err}"),
+            SourceError::Unsupported(what) => write!(f, "unsupported {what}"),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<alsa::Error> for SourceError {
+    fn from(err: alsa::Error) -> Self {
+        SourceError::Alsa(err)
+    }
+}
+
+impl From<io::Error> for SourceError {
+    fn from(err: io::Error) -> Self {
+        SourceError::Io(err)
+    }
+}
+
+/// Splits a stream of chunks into frames of a fixed number of interleaved samples starting
+/// every `hop` samples, so consecutive frames overlap when `hop` is below the frame length.
+/// Samples are scaled to full-scale 32-bit integers whatever the source's sample format.
+pub struct Framer {
+    len: usize,
+    hop: usize,
+    /// Left shift bringing integer samples of the source to 32 bits.
+    shift: u32,
+    /// Samples to drop before the next frame starts, when `hop` exceeds the frame length.
+    skip: usize,
+    frame: Vec<i32>,
+}
+
+impl Framer {
+    pub fn new(len: usize, hop: usize, spec: WavSpec) -> Self {
+        Self {
+            len,
+            hop,
+            shift: match spec.sample_format {
+                SampleFormat::Int => 32 - spec.bits_per_sample.min(32) as u32,
+                SampleFormat::Float => 0,
+            },
+            skip: 0,
+            frame: Vec::with_capacity(len),
+        }
+    }
+
+    /// Appends `chunk` and calls `on_frame` for every frame completed by it.
+    pub fn push<F: FnMut(&[i32])>(&mut self, chunk: &Chunk, mut on_frame: F) {
+        match chunk {
+            Chunk::Int(samples) => {
+                for sample in samples.iter() {
+                    self.push_sample(*sample << self.shift, &mut on_frame);
+                }
+            }
+            Chunk::Float(samples) => {
+                for sample in samples.iter() {
+                    self.push_sample(float_to_i32(*sample), &mut on_frame);
+                }
+            }
+        }
+    }
+
+    fn push_sample<F: FnMut(&[i32])>(&mut self, sample: i32, on_frame: &mut F) {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return;
+        }
+        self.frame.push(sample);
+        if self.frame.len() == self.len {
+            on_frame(&self.frame);
+            let consumed = self.hop.min(self.len);
+            self.frame.drain(..consumed);
+            self.skip = self.hop - consumed;
+        }
+    }
+}
+
+/// Which channels of an interleaved frame features are computed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelMode {
+    /// A single channel, counted from 0.
+    Select(usize),
+    /// The average of all channels.
+    Mix,
+    /// Every channel separately.
+    Each,
+}
+
+impl ChannelMode {
+    /// Number of signals `split` produces per frame.
+    pub fn rows(self, channels: usize) -> usize {
+        match self {
+            ChannelMode::Each => channels,
+            ChannelMode::Select(_) | ChannelMode::Mix => 1,
+        }
+    }
+
+    /// Deinterleaves `frame` of `channels` channels and calls `on_signal` with the index of
+    /// each resulting signal, below `rows`, and its samples.
+    pub fn split<F: FnMut(usize, &[i32])>(self, frame: &[i32], channels: usize, mut on_signal: F) {
+        match self {
+            ChannelMode::Select(channel) => {
+                let signal = frame[channel..].iter().step_by(channels).copied();
+                on_signal(0, &signal.collect::<Vec<_>>());
+            }
+            ChannelMode::Mix => {
+                let signal = frame.chunks_exact(channels).map(|samples| {
+                    let sum = samples.iter().map(|s| *s as i64).sum::<i64>();
+                    (sum / channels as i64) as i32
+                });
+                on_signal(0, &signal.collect::<Vec<_>>());
+            }
+            ChannelMode::Each => {
+                for channel in 0..channels {
+                    let signal = frame[channel..].iter().step_by(channels).copied();
+                    on_signal(channel, &signal.collect::<Vec<_>>());
+                }
+            }
+        }
+    }
+}
+
+/// A RIFF or RF64 WAV file.
+pub struct WavSource {
+    raw: RawSource<io::Take<BufReader<File>>>,
+    metadata: Metadata,
+}
+
+impl WavSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SourceError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let info = wav::read_header(&mut reader)?;
+        let width = wav::sample_width(&info.spec);
+        // Recordings that were never finalized state no length, their data runs to the end.
+        let data_len = match info.data_len {
+            0 | 0xffff_ffff => u64::MAX,
+            len => len,
+        };
+        Ok(Self {
+            raw: RawSource::with_spec(reader.take(data_len), info.spec, width),
+            metadata: info.metadata,
+        })
+    }
+}
+
+impl AudioSource for WavSource {
+    fn spec(&self) -> WavSpec {
+        self.raw.spec()
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        (!self.metadata.is_empty()).then_some(&self.metadata)
+    }
+
+    fn read(&mut self) -> Result<Option<Chunk<'_>>, SourceError> {
+        self.raw.read()
+    }
+}
+
+/// Audio file formats told apart by their first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Container {
+    Wav,
+    Flac,
+    Ogg,
+    Mp3,
+    Unknown,
+}
+
+/// Identifies the format of the file behind `reader` from its first bytes, looking past an
+/// ID3v2 tag since both FLAC and MP3 files may start with one.
+fn sniff<R: Read + Seek>(reader: &mut R) -> io::Result<Container> {
+    let mut head = Vec::with_capacity(10);
+    reader.by_ref().take(10).read_to_end(&mut head)?;
+    if head.len() == 10 && head.starts_with(b"ID3") {
+        let len = head[6..]
+            .iter()
+            .fold(0, |len, b| (len << 7) | (b & 0x7f) as u64);
+        let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+        reader.seek(SeekFrom::Start(10 + len + footer))?;
+        head.clear();
+        reader.by_ref().take(4).read_to_end(&mut head)?;
+    }
+    Ok(match head.as_slice() {
+        [b'R', b'I', b'F', b'F', ..] | [b'R', b'F', b'6', b'4', ..] => Container::Wav,
+        [b'f', b'L', b'a', b'C', ..] => Container::Flac,
+        [b'O', b'g', b'g', b'S', ..] => Container::Ogg,
+        // MPEG audio frame sync.
+        [0xff, second, ..] if second & 0xe0 == 0xe0 => Container::Mp3,
+        _ => Container::Unknown,
+    })
+}
+
+/// Opens an audio file with the decoder matching its content, whatever its extension.
+pub fn open_file<P: AsRef<Path>>(path: P) -> Result<Box<dyn AudioSource + Send>, SourceError> {
+    let path = path.as_ref();
+    match sniff(&mut File::open(path)?)? {
+        Container::Wav => Ok(Box::new(WavSource::open(path)?)),
+        Container::Flac => Ok(Box::new(FlacSource::open(path)?)),
+        // There is no decoder for lossy formats yet.
+        Container::Ogg => Err(SourceError::Unsupported(
+            "Ogg file, convert it to WAV or FLAC first".to_owned(),
+        )),
+        Container::Mp3 => Err(SourceError::Unsupported(
+            "MP3 file, convert it to WAV or FLAC first".to_owned(),
+        )),
+        Container::Unknown => Err(SourceError::Unsupported(format!(
+            "file format of {}, expected WAV or FLAC",
+            path.display()
+        ))),
+    }
+}
+
+/// Returns the WAV spec matching a PCM format, together with the number of bytes each sample
+/// occupies.
+pub fn pcm_format_spec(
+    format: Format,
+    channels: u16,
+    sample_rate: u32,
+) -> Option<(WavSpec, usize)> {
+    let (bits_per_sample, sample_format, width) = match format {
+        Format::S16LE | Format::S16BE => (16, SampleFormat::Int, 2),
+        Format::S243LE => (24, SampleFormat::Int, 3),
+        Format::S24LE => (24, SampleFormat::Int, 4),
+        Format::S32LE | Format::S32BE => (32, SampleFormat::Int, 4),
+        Format::FloatLE | Format::FloatBE => (32, SampleFormat::Float, 4),
+        _ => return None,
+    };
+    Some((
+        WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        },
+        width,
+    ))
+}
+
+/// Whether samples of a PCM format `pcm_format_spec` accepts are stored most significant byte
+/// first.
+pub fn is_big_endian(format: Format) -> bool {
+    matches!(format, Format::S16BE | Format::S32BE | Format::FloatBE)
+}
+
+/// Decodes packed samples of `spec` stored in `width` bytes each into `ints` or `floats`,
+/// whichever the sample format calls for. 8-bit samples are unsigned, as in WAV files.
+pub fn decode_pcm<'a>(
+    bytes: &[u8],
+    spec: WavSpec,
+    width: usize,
+    big_endian: bool,
+    ints: &'a mut Vec<i32>,
+    floats: &'a mut Vec<f32>,
+) -> Chunk<'a> {
+    // Byte order of the sample as little-endian, so one conversion handles both.
+    let word = |b: &[u8]| {
+        if big_endian {
+            [b[3], b[2], b[1], b[0]]
+        } else {
+            [b[0], b[1], b[2], b[3]]
+        }
+    };
+    match (spec.sample_format, width) {
+        (SampleFormat::Float, _) => {
+            floats.clear();
+            floats.extend(bytes.chunks_exact(4).map(|b| f32::from_le_bytes(word(b))));
+            Chunk::Float(floats)
+        }
+        (SampleFormat::Int, 1) => {
+            ints.clear();
+            ints.extend(bytes.iter().map(|b| *b as i32 - 128));
+            Chunk::Int(ints)
+        }
+        (SampleFormat::Int, 2) => {
+            ints.clear();
+            let order = |b: &[u8]| {
+                if big_endian {
+                    [b[1], b[0]]
+                } else {
+                    [b[0], b[1]]
+                }
+            };
+            ints.extend(
+                bytes
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes(order(b)) as i32),
+            );
+            Chunk::Int(ints)
+        }
+        (SampleFormat::Int, 3) => {
+            unpack_s24_3le(bytes, ints);
+            Chunk::Int(ints)
+        }
+        (SampleFormat::Int, _) => {
+            ints.clear();
+            let shift = 32 - spec.bits_per_sample;
+            ints.extend(
+                bytes
+                    .chunks_exact(4)
+                    .map(|b| (i32::from_le_bytes(word(b)) << shift) >> shift),
+            );
+            Chunk::Int(ints)
+        }
+    }
+}
+
+/// Replaces the contents of `out` with packed 24-bit little-endian samples, sign extended.
+pub fn unpack_s24_3le(bytes: &[u8], out: &mut Vec<i32>) {
+    out.clear();
+    out.extend(
+        bytes
+            .chunks_exact(3)
+            .map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8),
+    );
+}
+
+/// Headerless PCM read from any byte stream, e.g. stdin.
+pub struct RawSource<R> {
+    reader: R,
+    spec: WavSpec,
+    width: usize,
+    big_endian: bool,
+    bytes: Vec<u8>,
+    filled: usize,
+    int_buf: Vec<i32>,
+    float_buf: Vec<f32>,
+}
+
+impl<R: Read> RawSource<R> {
+    pub fn new(
+        reader: R,
+        format: Format,
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Self, SourceError> {
+        let (spec, width) = pcm_format_spec(format, channels, sample_rate)
+            .ok_or_else(|| SourceError::Unsupported(format!("raw format {format}")))?;
+        Ok(Self {
+            big_endian: is_big_endian(format),
+            ..Self::with_spec(reader, spec, width)
+        })
+    }
+
+    /// Reads little-endian samples of `spec` stored in `width` bytes each. 8-bit samples are
+    /// unsigned, as in WAV files.
+    fn with_spec(reader: R, spec: WavSpec, width: usize) -> Self {
+        Self {
+            reader,
+            spec,
+            width,
+            big_endian: false,
+            bytes: vec![0; CHUNK_FRAMES * spec.channels as usize * width],
+            filled: 0,
+            int_buf: Vec::new(),
+            float_buf: Vec::new(),
+        }
+    }
+}
+
+impl<R: Read> AudioSource for RawSource<R> {
+    fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    fn read(&mut self) -> Result<Option<Chunk<'_>>, SourceError> {
+        let frame_bytes = self.width * self.spec.channels as usize;
+        let usable = loop {
+            let n = match self.reader.read(&mut self.bytes[self.filled..]) {
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if n == 0 {
+                // A trailing partial frame cannot be interpreted, drop it.
+                return Ok(None);
+            }
+            self.filled += n;
+            let usable = self.filled - self.filled % frame_bytes;
+            if usable > 0 {
+                break usable;
+            }
+        };
+
+        let chunk = decode_pcm(
+            &self.bytes[..usable],
+            self.spec,
+            self.width,
+            self.big_endian,
+            &mut self.int_buf,
+            &mut self.float_buf,
+        );
+        self.bytes.copy_within(usable..self.filled, 0);
+        self.filled -= usable;
+        Ok(Some(chunk))
+    }
+}
+
+/// Generates a sine tone mixed with white noise, identical on every channel except for the noise.
+pub struct SyntheticSource {
+    spec: WavSpec,
+    tone_hz: f32,
+    tone_level: f32,
+    noise_level: f32,
+    remaining_frames: Option<u64>,
+    position: u64,
+    rng: u64,
+    int_buf: Vec<i32>,
+    float_buf: Vec<f32>,
+}
+
+impl SyntheticSource {
+    pub fn new(
+        spec: WavSpec,
+        tone_hz: f32,
+        tone_level: f32,
+        noise_level: f32,
+        duration_secs: Option<f32>,
+    ) -> Self {
+        Self {
+            spec,
+            tone_hz,
+            tone_level,
+            noise_level,
+            remaining_frames: duration_secs.map(|d| (d * spec.sample_rate as f32) as u64),
+            position: 0,
+            rng: 0x2545_f491_4f6c_dd1d,
+            int_buf: Vec::new(),
+            float_buf: Vec::new(),
+        }
+    }
+
+    /// xorshift64*, mapped to `[-1, 1)`.
+    fn noise(&mut self) -> f32 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let r = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (r >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+impl AudioSource for SyntheticSource {
+    fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    fn read(&mut self) -> Result<Option<Chunk<'_>>, SourceError> {
+        let mut frames = CHUNK_FRAMES as u64;
+        if let Some(remaining) = self.remaining_frames.as_mut() {
+            frames = frames.min(*remaining);
+            *remaining -= frames;
+        }
+        if frames == 0 {
+            return Ok(None);
+        }
+
+        let step = std::f64::consts::TAU * self.tone_hz as f64 / self.spec.sample_rate as f64;
+        let scale = ((1i64 << (self.spec.bits_per_sample - 1)) - 1) as f32;
+        self.int_buf.clear();
+        self.float_buf.clear();
+        for _ in 0..frames {
+            let tone = self.tone_level * (step * self.position as f64).sin() as f32;
+            self.position += 1;
+            for _ in 0..self.spec.channels {
+                let sample = (tone + self.noise_level * self.noise()).clamp(-1.0, 1.0);
+                match self.spec.sample_format {
+                    SampleFormat::Int => self.int_buf.push((sample * scale) as i32),
+                    SampleFormat::Float => self.float_buf.push(sample),
+                }
+            }
+        }
+
+        Ok(Some(match self.spec.sample_format {
+            SampleFormat::Int => Chunk::Int(&self.int_buf),
+            SampleFormat::Float => Chunk::Float(&self.float_buf),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(bits_per_sample: u16, sample_format: SampleFormat) -> WavSpec {
+        WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+
+    fn ints(format: Format, bytes: &[u8]) -> Vec<i32> {
+        let (spec, width) = pcm_format_spec(format, 1, 48000).unwrap();
+        let (mut ints, mut floats) = (Vec::new(), Vec::new());
+        let big_endian = is_big_endian(format);
+        match decode_pcm(bytes, spec, width, big_endian, &mut ints, &mut floats) {
+            Chunk::Int(samples) => samples.to_vec(),
+            Chunk::Float(_) => panic!("float chunk for {format}"),
+        }
+    }
+
+    #[test]
+    fn decodes_both_byte_orders() {
+        assert_eq!(ints(Format::S16LE, &[0x34, 0x12, 0xfe, 0xff]), [0x1234, -2]);
+        assert_eq!(ints(Format::S16BE, &[0x12, 0x34, 0xff, 0xfe]), [0x1234, -2]);
+        assert_eq!(
+            ints(Format::S32LE, &[4, 3, 2, 0x81]),
+            [0x8102_0304u32 as i32]
+        );
+        assert_eq!(
+            ints(Format::S32BE, &[0x81, 2, 3, 4]),
+            [0x8102_0304u32 as i32]
+        );
+        assert_eq!(ints(Format::S24LE, &[0xff, 0xff, 0xff, 0]), [-1]);
+        assert_eq!(ints(Format::S243LE, &[0xfe, 0xff, 0x7f]), [0x7f_fffe]);
+
+        let bytes = (-0.5f32).to_be_bytes();
+        let (mut ints, mut floats) = (Vec::new(), Vec::new());
+        let chunk = decode_pcm(
+            &bytes,
+            spec(32, SampleFormat::Float),
+            4,
+            true,
+            &mut ints,
+            &mut floats,
+        );
+        assert!(matches!(chunk, Chunk::Float([sample]) if *sample == -0.5));
+    }
+
+    /// Frames `framer` cuts from `samples`, fed in chunks of `chunk` samples.
+    fn frames(mut framer: Framer, samples: &[i32], chunk: usize) -> Vec<Vec<i32>> {
+        let mut frames = Vec::new();
+        for piece in samples.chunks(chunk) {
+            framer.push(&Chunk::Int(piece), |frame| frames.push(frame.to_vec()));
+        }
+        frames
+    }
+
+    #[test]
+    fn framer_overlaps_frames_when_hop_is_shorter() {
+        let samples = (0..11).collect::<Vec<_>>();
+        let expected = [[0, 1, 2, 3], [2, 3, 4, 5], [4, 5, 6, 7], [6, 7, 8, 9]];
+        for chunk in [1, 3, 11] {
+            let framer = Framer::new(4, 2, spec(32, SampleFormat::Int));
+            assert_eq!(
+                frames(framer, &samples, chunk),
+                expected,
+                "chunks of {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn framer_skips_samples_when_hop_is_longer() {
+        let samples = (0..14).collect::<Vec<_>>();
+        let expected = [[0, 1, 2], [5, 6, 7], [10, 11, 12]];
+        for chunk in [1, 4, 14] {
+            let framer = Framer::new(3, 5, spec(32, SampleFormat::Int));
+            assert_eq!(
+                frames(framer, &samples, chunk),
+                expected,
+                "chunks of {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn framer_scales_samples_to_32_bits() {
+        let framer = Framer::new(2, 2, spec(16, SampleFormat::Int));
+        assert_eq!(frames(framer, &[1, -32768], 2), [[1 << 16, i32::MIN]]);
+
+        let mut framer = Framer::new(2, 2, spec(32, SampleFormat::Float));
+        let mut frames = Vec::new();
+        framer.push(&Chunk::Float(&[0.5, -2.0]), |frame| {
+            frames.push(frame.to_vec())
+        });
+        assert_eq!(frames, [[float_to_i32(0.5), -i32::MAX]]);
+    }
+
+    #[test]
+    fn raw_source_reads_big_endian_frames() {
+        let bytes: &[u8] = &[0x00, 0x01, 0x80, 0x00, 0x7f];
+        let mut raw = RawSource::new(bytes, Format::S16BE, 1, 48000).unwrap();
+        match raw.read().unwrap() {
+            // The trailing odd byte is not a whole frame.
+            Some(Chunk::Int(samples)) => assert_eq!(samples, [1, -32768]),
+            _ => panic!("expected integer samples"),
+        }
+        assert!(raw.read().unwrap().is_none());
+    }
+}
@@ -0,0 +1,134 @@
+//! Spectral summary statistics: a handful of values describing the shape of the spectrum,
+//! for models small enough to run on the sensors themselves.
+
+use crate::features::FeatureConfig;
+
+/// Smallest power, keeping ratios of silent frames finite.
+const TINY: f32 = 1e-30;
+/// Lowest level reported in dB, for silent frames and bands.
+const FLOOR_DB: f32 = -120.0;
+
+/// Statistics of the power spectrum, in order: centroid, bandwidth, flatness, rolloff,
+/// crest factor and kurtosis, followed by the share of the energy in each of the
+/// `energy_bands` bands in dB and the RMS of the frame in dB relative to full scale.
+///
+/// `magnitudes` are the FFT magnitudes at `freqs` of a frame whose samples in `[-1, 1]` have
+/// an RMS of `rms`.
+pub fn summary(freqs: &[f32], magnitudes: &[f32], rms: f32, config: &FeatureConfig) -> Vec<f32> {
+    let power = magnitudes.iter().map(|m| m * m).collect::<Vec<_>>();
+    let sum = power.iter().sum::<f32>();
+    let total = sum.max(TINY);
+    let mean = (sum / power.len() as f32).max(TINY);
+    let moment = |centre: f32, order: i32| {
+        freqs
+            .iter()
+            .zip(&power)
+            .map(|(f, p)| (f - centre).powi(order) * p)
+            .sum::<f32>()
+            / total
+    };
+
+    let centroid = moment(0.0, 1);
+    let bandwidth = moment(centroid, 2).sqrt();
+    let kurtosis = moment(centroid, 4) / bandwidth.powi(4).max(TINY);
+    let log_mean = power.iter().map(|p| p.max(TINY).ln()).sum::<f32>() / power.len() as f32;
+    let flatness = log_mean.exp() / mean;
+    let crest = power.iter().copied().fold(0.0, f32::max) / mean;
+    let mut cumulative = 0.0;
+    let rolloff = freqs
+        .iter()
+        .zip(&power)
+        .find(|(_, p)| {
+            cumulative += *p;
+            cumulative >= config.rolloff * total
+        })
+        .map_or(freqs[freqs.len() - 1], |(f, _)| *f);
+
+    let mut values = vec![centroid, bandwidth, flatness, rolloff, crest, kurtosis];
+    let edges = band_edges(config);
+    values.extend(edges.windows(2).map(|band| {
+        let energy = freqs
+            .iter()
+            .zip(&power)
+            .filter(|(f, _)| band[0] <= **f && **f < band[1])
+            .map(|(_, p)| p)
+            .sum::<f32>();
+        (10.0 * (energy / total).log10()).max(FLOOR_DB)
+    }));
+    values.push((20.0 * rms.log10()).max(FLOOR_DB));
+    values
+}
+
+/// Edges of the energy bands, spaced evenly on a logarithmic scale from `min_freq` to just
+/// past `max_freq`, so the top bin falls in the last band.
+fn band_edges(config: &FeatureConfig) -> Vec<f32> {
+    let low = config.min_freq.max(1.0).ln();
+    let high = config.max_freq.ln();
+    let bands = config.energy_bands;
+    let mut edges = (0..=bands)
+        .map(|i| (low + (high - low) * i as f32 / bands as f32).exp())
+        .collect::<Vec<_>>();
+    edges[0] = config.min_freq;
+    edges[bands] = f32::INFINITY;
+    edges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn freqs() -> Vec<f32> {
+        (1..=2000).map(|i| i as f32 * 2.0).collect()
+    }
+
+    #[test]
+    fn tone_centroid_sits_at_the_tone() {
+        let config = FeatureConfig::default();
+        let freqs = freqs();
+        let magnitudes = freqs
+            .iter()
+            .map(|f| match (f - 1000.0).abs() {
+                0.0 => 1.0,
+                2.0 => 0.5,
+                _ => 1e-4,
+            })
+            .collect::<Vec<_>>();
+        let values = summary(&freqs, &magnitudes, 0.5, &config);
+        assert_eq!(values.len(), 6 + config.energy_bands + 1);
+        let [centroid, bandwidth, flatness, rolloff, ..] = values[..] else {
+            unreachable!()
+        };
+        assert!((centroid - 1000.0).abs() < 5.0, "centroid {centroid}");
+        assert!(bandwidth < 20.0, "bandwidth {bandwidth}");
+        assert!(flatness < 0.01, "flatness {flatness}");
+        assert!((rolloff - 1000.0).abs() <= 2.0, "rolloff {rolloff}");
+        assert!((values[values.len() - 1] - 20.0 * 0.5f32.log10()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn white_noise_is_flat() {
+        let config = FeatureConfig::default();
+        let freqs = freqs();
+        // Power of white noise averaged over 64 frames: exponentially distributed per frame.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut uniform = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f32 / (1u64 << 53) as f32
+        };
+        let magnitudes = freqs
+            .iter()
+            .map(|_| {
+                let power = (0..64).map(|_| -(1.0 - uniform()).ln()).sum::<f32>() / 64.0;
+                power.sqrt()
+            })
+            .collect::<Vec<_>>();
+        let values = summary(&freqs, &magnitudes, 0.1, &config);
+        let [centroid, _, flatness, ..] = values[..] else {
+            unreachable!()
+        };
+        assert!(flatness > 0.95, "flatness {flatness}");
+        assert!((centroid - 2001.0).abs() < 50.0, "centroid {centroid}");
+    }
+}
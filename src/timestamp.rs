@@ -0,0 +1,141 @@
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A UTC calendar date and time.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self::from_system_time(SystemTime::now())
+    }
+
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs() as i64;
+        let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+        let secs_of_day = secs.rem_euclid(86400) as u32;
+        Self {
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+        }
+    }
+
+    /// `YYYYMMDD`, for file names.
+    pub fn compact_date(&self) -> String {
+        format!("{:04}{:02}{:02}", self.year, self.month, self.day)
+    }
+
+    /// `HHMMSS`, for file names.
+    pub fn compact_time(&self) -> String {
+        format!("{:02}{:02}{:02}", self.hour, self.minute, self.second)
+    }
+
+    /// `YYYY-MM-DD`.
+    pub fn iso_date(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    /// `HH:MM:SS`.
+    pub fn iso_time(&self) -> String {
+        format!("{:02}:{:02}:{:02}", self.hour, self.minute, self.second)
+    }
+
+    /// Parses a `YYYY-MM-DD` date and an `HH:MM:SS` time. Any single character is accepted
+    /// as separator, as in BWF.
+    pub fn parse(date: &str, time: &str) -> Option<Self> {
+        if date.len() != 10 || time.len() != 8 {
+            return None;
+        }
+        let stamp = Self {
+            year: digits(date, 0, 4)?,
+            month: digits(date, 5, 2)?,
+            day: digits(date, 8, 2)?,
+            hour: digits(time, 0, 2)?,
+            minute: digits(time, 3, 2)?,
+            second: digits(time, 6, 2)?,
+        };
+        let valid = (1..=12).contains(&stamp.month)
+            && (1..=31).contains(&stamp.day)
+            && stamp.hour < 24
+            && stamp.minute < 60
+            && stamp.second < 60;
+        valid.then_some(stamp)
+    }
+}
+
+/// Parses the `len` characters of `s` starting at `at` as a number.
+fn digits<T: FromStr>(s: &str, at: usize, len: usize) -> Option<T> {
+    s.get(at..at + len)?.parse().ok()
+}
+
+/// Converts days since 1970-01-01 to a proleptic Gregorian (year, month, day).
+/// See Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn converts_days_across_leap_days_and_centuries() {
+        for (days, date) in [
+            (0, (1970, 1, 1)),
+            (-1, (1969, 12, 31)),
+            (11016, (2000, 2, 29)),
+            (11017, (2000, 3, 1)),
+            (19782, (2024, 2, 29)),
+            (47540, (2100, 2, 28)),
+            (47541, (2100, 3, 1)),
+            (-25508, (1900, 3, 1)),
+            (-135081, (1600, 2, 29)),
+        ] {
+            assert_eq!(civil_from_days(days), date, "day {days}");
+        }
+    }
+
+    #[test]
+    fn splits_system_time_into_fields() {
+        let stamp = Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(951_868_799));
+        assert_eq!(stamp.iso_date(), "2000-02-29");
+        assert_eq!(stamp.iso_time(), "23:59:59");
+        assert_eq!(stamp.compact_date(), "20000229");
+        assert_eq!(stamp.compact_time(), "235959");
+    }
+
+    #[test]
+    fn parses_bwf_dates_and_times() {
+        let stamp = Timestamp::parse("2023-11-14", "22.13.20").unwrap();
+        assert_eq!(stamp.iso_date(), "2023-11-14");
+        assert_eq!(stamp.iso_time(), "22:13:20");
+        assert!(Timestamp::parse("2023-13-14", "22:13:20").is_none());
+        assert!(Timestamp::parse("2023-11-14", "24:00:00").is_none());
+        assert!(Timestamp::parse("", "").is_none());
+    }
+}
@@ -0,0 +1,578 @@
+//! RIFF/WAVE reading and writing with RF64 support, for recordings larger than 4 GiB.
+//!
+//! The writer reserves a `JUNK` chunk the size of a `ds64` chunk right after the RIFF header.
+//! Files that stay below 4 GiB are ordinary WAV files; larger ones are turned into RF64 by
+//! replacing the `JUNK` chunk with `ds64` when the header is written. Recording metadata goes
+//! into `bext` and `iXML` chunks between `fmt ` and `data`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use hound::{SampleFormat, WavSpec};
+
+use crate::bwf::{self, Metadata};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// Size of the `ds64` chunk body without a table.
+const DS64_LEN: u32 = 28;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Bytes one sample of `spec` occupies in the data chunk.
+pub fn sample_width(spec: &WavSpec) -> usize {
+    spec.bits_per_sample.div_ceil(8) as usize
+}
+
+/// Body of the `fmt ` chunk describing `spec`.
+fn fmt_chunk(spec: &WavSpec) -> Vec<u8> {
+    let width = sample_width(spec) as u16;
+    let block_align = width * spec.channels;
+    let tag = match spec.sample_format {
+        SampleFormat::Int => WAVE_FORMAT_PCM,
+        SampleFormat::Float => WAVE_FORMAT_IEEE_FLOAT,
+    };
+    let extensible = spec.channels > 2;
+    let format_tag = if extensible {
+        WAVE_FORMAT_EXTENSIBLE
+    } else {
+        tag
+    };
+
+    let mut fmt = Vec::with_capacity(40);
+    fmt.extend_from_slice(&format_tag.to_le_bytes());
+    fmt.extend_from_slice(&spec.channels.to_le_bytes());
+    fmt.extend_from_slice(&spec.sample_rate.to_le_bytes());
+    fmt.extend_from_slice(&(spec.sample_rate * block_align as u32).to_le_bytes());
+    fmt.extend_from_slice(&block_align.to_le_bytes());
+    fmt.extend_from_slice(&(width * 8).to_le_bytes());
+    if extensible {
+        fmt.extend_from_slice(&22u16.to_le_bytes());
+        fmt.extend_from_slice(&spec.bits_per_sample.to_le_bytes());
+        // Unspecified channel positions.
+        fmt.extend_from_slice(&0u32.to_le_bytes());
+        fmt.extend_from_slice(&tag.to_le_bytes());
+        fmt.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+        ]);
+    }
+    fmt
+}
+
+/// Writes a chunk with a body small enough to be known up front, with its pad byte.
+fn write_chunk<W: Write>(w: &mut W, id: &[u8; 4], body: &[u8]) -> io::Result<()> {
+    w.write_all(id)?;
+    w.write_all(&(body.len() as u32).to_le_bytes())?;
+    w.write_all(body)?;
+    if body.len() % 2 == 1 {
+        w.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+/// Writes the RIFF chunk sizes for a file of `file_len` bytes whose data chunk holds
+/// `data_len` bytes and has its size field at `data_size_pos`. Files past 4 GiB are written
+/// as RF64, which needs the 28-byte slot at offset 12 that `WavWriter` reserves.
+fn write_sizes<W: Write + Seek>(
+    w: &mut W,
+    spec: &WavSpec,
+    file_len: u64,
+    data_size_pos: u64,
+    data_len: u64,
+) -> io::Result<()> {
+    let riff_size = file_len - 8;
+    if riff_size > u32::MAX as u64 {
+        let frames = data_len / (sample_width(spec) as u64 * spec.channels as u64);
+        w.seek(SeekFrom::Start(0))?;
+        w.write_all(b"RF64")?;
+        w.write_all(&u32::MAX.to_le_bytes())?;
+        w.write_all(b"WAVE")?;
+        w.write_all(b"ds64")?;
+        w.write_all(&DS64_LEN.to_le_bytes())?;
+        w.write_all(&riff_size.to_le_bytes())?;
+        w.write_all(&data_len.to_le_bytes())?;
+        w.write_all(&frames.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.seek(SeekFrom::Start(data_size_pos))?;
+        w.write_all(&u32::MAX.to_le_bytes())?;
+    } else {
+        w.seek(SeekFrom::Start(4))?;
+        w.write_all(&(riff_size as u32).to_le_bytes())?;
+        w.seek(SeekFrom::Start(data_size_pos))?;
+        w.write_all(&(data_len as u32).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Streams interleaved samples to a WAV file, switching to RF64 once it outgrows 4 GiB.
+pub struct WavWriter<W: Write + Seek> {
+    inner: W,
+    spec: WavSpec,
+    /// Offset of the `data` chunk's size field.
+    data_size_pos: u64,
+    data_bytes: u64,
+    scratch: Vec<u8>,
+}
+
+impl WavWriter<BufWriter<File>> {
+    /// Makes everything written so far survive a crash or power loss: updates the header
+    /// sizes and syncs the file to disk.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.inner.flush()?;
+        self.inner.get_ref().sync_data()
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut inner: W, spec: WavSpec, metadata: Option<&Metadata>) -> io::Result<Self> {
+        inner.write_all(b"RIFF")?;
+        inner.write_all(&0u32.to_le_bytes())?;
+        inner.write_all(b"WAVE")?;
+        inner.write_all(b"JUNK")?;
+        inner.write_all(&DS64_LEN.to_le_bytes())?;
+        inner.write_all(&[0; DS64_LEN as usize])?;
+        write_chunk(&mut inner, b"fmt ", &fmt_chunk(&spec))?;
+        if let Some(metadata) = metadata {
+            write_chunk(&mut inner, b"bext", &bwf::bext_chunk(metadata, &spec))?;
+            write_chunk(&mut inner, b"iXML", &bwf::ixml_chunk(metadata))?;
+        }
+        inner.write_all(b"data")?;
+        let data_size_pos = inner.stream_position()?;
+        inner.write_all(&0u32.to_le_bytes())?;
+        Ok(Self {
+            inner,
+            spec,
+            data_size_pos,
+            data_bytes: 0,
+            scratch: Vec::new(),
+        })
+    }
+
+    /// Writes integer samples using the full range of the spec's `bits_per_sample`.
+    pub fn write_int(&mut self, samples: &[i32]) -> io::Result<()> {
+        let width = sample_width(&self.spec);
+        self.scratch.clear();
+        for sample in samples {
+            let bytes = sample.to_le_bytes();
+            match width {
+                1 => self.scratch.push((*sample + 128) as u8),
+                _ => self.scratch.extend_from_slice(&bytes[..width]),
+            }
+        }
+        self.inner.write_all(&self.scratch)?;
+        self.data_bytes += self.scratch.len() as u64;
+        Ok(())
+    }
+
+    pub fn write_float(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.scratch.clear();
+        for sample in samples {
+            self.scratch.extend_from_slice(&sample.to_le_bytes());
+        }
+        self.inner.write_all(&self.scratch)?;
+        self.data_bytes += self.scratch.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the chunk sizes for the data written so far.
+    fn write_header(&mut self) -> io::Result<()> {
+        let end = self.inner.stream_position()?;
+        write_sizes(
+            &mut self.inner,
+            &self.spec,
+            end,
+            self.data_size_pos,
+            self.data_bytes,
+        )?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> io::Result<()> {
+        if self.data_bytes % 2 == 1 {
+            self.inner.write_all(&[0])?;
+        }
+        self.write_header()?;
+        self.inner.flush()
+    }
+}
+
+/// Layout of a WAV file's sample data.
+#[derive(Debug, Clone)]
+pub struct WavInfo {
+    pub spec: WavSpec,
+    /// Offset of the first sample in the file.
+    pub data_offset: u64,
+    /// Length of the sample data in bytes as stated by the header. Files that were never
+    /// finalized usually state 0 or `u32::MAX`.
+    pub data_len: u64,
+    /// Length of the whole file in bytes as stated by the header.
+    pub file_len: u64,
+    /// Contents of the `bext` and `iXML` chunks before the sample data.
+    pub metadata: Metadata,
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn skip<R: Read>(reader: &mut R, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.by_ref().take(len), &mut io::sink())?;
+    if skipped < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Reads a chunk body of `len` bytes and its pad byte.
+fn read_body<R: Read>(reader: &mut R, len: u32) -> io::Result<Vec<u8>> {
+    // Grown as the bytes arrive rather than allocated up front, as `len` comes from the file.
+    let mut body = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut body)?;
+    if body.len() < len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if len % 2 == 1 {
+        skip(reader, 1)?;
+    }
+    Ok(body)
+}
+
+fn parse_fmt(fmt: &[u8]) -> io::Result<WavSpec> {
+    if fmt.len() < 16 {
+        return Err(invalid("fmt chunk too short"));
+    }
+    let mut tag = read_u16(fmt, 0);
+    if tag == WAVE_FORMAT_EXTENSIBLE {
+        if fmt.len() < 26 {
+            return Err(invalid("extensible fmt chunk too short"));
+        }
+        tag = read_u16(fmt, 24);
+    }
+    let sample_format = match tag {
+        WAVE_FORMAT_PCM => SampleFormat::Int,
+        WAVE_FORMAT_IEEE_FLOAT => SampleFormat::Float,
+        _ => return Err(invalid("unsupported WAV sample format")),
+    };
+    let spec = WavSpec {
+        channels: read_u16(fmt, 2),
+        sample_rate: read_u32(fmt, 4),
+        bits_per_sample: read_u16(fmt, 14),
+        sample_format,
+    };
+    let valid = match spec.sample_format {
+        SampleFormat::Int => matches!(spec.bits_per_sample, 8 | 16 | 24 | 32),
+        SampleFormat::Float => spec.bits_per_sample == 32,
+    };
+    if !valid || spec.channels == 0 {
+        return Err(invalid("unsupported WAV sample layout"));
+    }
+    Ok(spec)
+}
+
+/// Reads a RIFF or RF64 header up to the start of the sample data.
+pub fn read_header<R: Read>(reader: &mut R) -> io::Result<WavInfo> {
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    let rf64 = match &header[..4] {
+        b"RIFF" => false,
+        b"RF64" => true,
+        _ => return Err(invalid("not a RIFF or RF64 file")),
+    };
+    if &header[8..] != b"WAVE" {
+        return Err(invalid("not a WAVE file"));
+    }
+
+    let riff_len = read_u32(&header, 4);
+    let mut spec = None;
+    let mut ds64 = None;
+    let mut metadata = Metadata::default();
+    let mut pos = 12;
+    loop {
+        let mut chunk = [0; 8];
+        reader.read_exact(&mut chunk)?;
+        let len = read_u32(&chunk, 4);
+        pos += 8;
+        match &chunk[..4] {
+            b"ds64" if rf64 => {
+                let body = read_body(reader, len)?;
+                if body.len() < 16 {
+                    return Err(invalid("ds64 chunk too short"));
+                }
+                ds64 = Some((read_u64(&body, 0), read_u64(&body, 8)));
+            }
+            b"fmt " => {
+                spec = Some(parse_fmt(&read_body(reader, len)?)?);
+            }
+            b"bext" => bwf::parse_bext(&read_body(reader, len)?, &mut metadata),
+            b"iXML" => bwf::parse_ixml(&read_body(reader, len)?, &mut metadata),
+            b"data" => {
+                let spec = spec.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+                let data_len = match ds64 {
+                    Some((_, data_len)) if len == u32::MAX => data_len,
+                    _ => len as u64,
+                };
+                let riff_len = match ds64 {
+                    Some((riff_size, _)) if riff_len == u32::MAX => riff_size,
+                    _ => riff_len as u64,
+                };
+                return Ok(WavInfo {
+                    spec,
+                    data_offset: pos,
+                    data_len,
+                    file_len: riff_len + 8,
+                    metadata,
+                });
+            }
+            _ => skip(reader, len as u64 + (len % 2) as u64)?,
+        }
+        pos += len as u64 + (len % 2) as u64;
+    }
+}
+
+/// Fixes the chunk sizes of a WAV file that was not finalized, e.g. because the recorder was
+/// killed, assuming the sample data runs to the end of the file. A trailing partial frame is
+/// cut off. Returns the corrected layout, or `None` if the header already matched the file,
+/// i.e. stated its length and held all of the sample data it claimed.
+pub fn repair<P: AsRef<Path>>(path: P) -> io::Result<Option<WavInfo>> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut info = read_header(&mut BufReader::new(&mut file))?;
+
+    let data_end = info.data_offset + info.data_len + info.data_len % 2;
+    if info.file_len == file_len && data_end <= file_len {
+        return Ok(None);
+    }
+
+    let block_align = sample_width(&info.spec) as u64 * info.spec.channels as u64;
+    info.data_len = (file_len - info.data_offset) / block_align * block_align;
+    let mut file_len = info.data_offset + info.data_len;
+    file.set_len(file_len)?;
+    if info.data_len % 2 == 1 {
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&[0])?;
+        file_len += 1;
+    }
+
+    if file_len - 8 > u32::MAX as u64 {
+        let mut slot = [0; 8];
+        file.seek(SeekFrom::Start(12))?;
+        file.read_exact(&mut slot)?;
+        if !matches!(&slot[..4], b"JUNK" | b"ds64") || read_u32(&slot, 4) < DS64_LEN {
+            return Err(invalid(
+                "file exceeds 4 GiB but has no room for a ds64 chunk",
+            ));
+        }
+    }
+    write_sizes(
+        &mut file,
+        &info.spec,
+        file_len,
+        info.data_offset - 4,
+        info.data_len,
+    )?;
+    file.sync_all()?;
+    Ok(Some(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor, path::PathBuf};
+
+    use super::*;
+
+    fn spec() -> WavSpec {
+        WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("wav-{}-{name}.wav", std::process::id()))
+    }
+
+    fn create(path: &Path) -> WavWriter<BufWriter<File>> {
+        WavWriter::new(BufWriter::new(File::create(path).unwrap()), spec(), None).unwrap()
+    }
+
+    /// Writes `frames` stereo frames, checkpoints, then writes `more` frames and stops
+    /// without finalizing, as if the recorder had been killed.
+    fn interrupted(path: &Path, frames: usize, more: usize) {
+        let mut writer = create(path);
+        writer.write_int(&vec![1; frames * 2]).unwrap();
+        writer.checkpoint().unwrap();
+        writer.write_int(&vec![2; more * 2]).unwrap();
+    }
+
+    #[test]
+    fn finalized_file_is_left_alone() {
+        let path = temp_path("finalized");
+        let mut writer = create(&path);
+        writer.write_int(&[0; 200]).unwrap();
+        writer.finalize().unwrap();
+        assert!(repair(&path).unwrap().is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checkpointed_file_is_left_alone() {
+        let path = temp_path("checkpointed");
+        interrupted(&path, 100, 0);
+        assert!(repair(&path).unwrap().is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn repairs_audio_written_after_the_last_checkpoint() {
+        let path = temp_path("interrupted");
+        interrupted(&path, 100, 50);
+        let info = repair(&path).unwrap().unwrap();
+        assert_eq!(info.data_len, 150 * 4);
+
+        let header = read_header(&mut BufReader::new(File::open(&path).unwrap())).unwrap();
+        assert_eq!(header.data_len, 150 * 4);
+        assert_eq!(header.file_len, fs::metadata(&path).unwrap().len());
+        assert!(repair(&path).unwrap().is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn repairs_file_never_checkpointed_and_cuts_partial_frame() {
+        let path = temp_path("partial");
+        let mut writer = create(&path);
+        writer.write_int(&[3; 21]).unwrap();
+        drop(writer);
+        let info = repair(&path).unwrap().unwrap();
+        assert_eq!(info.data_len, 10 * 4);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            info.data_offset + info.data_len
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    /// An RF64 file as `write_sizes` lays it out, stating `riff_size` and `data_len` only in
+    /// its `ds64` chunk, holding `data` followed by a trailing chunk.
+    fn rf64(riff_size: u64, data_len: u64, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RF64");
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(b"ds64");
+        bytes.extend_from_slice(&DS64_LEN.to_le_bytes());
+        bytes.extend_from_slice(&riff_size.to_le_bytes());
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        bytes.extend_from_slice(&(data_len / 4).to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        write_chunk(&mut bytes, b"fmt ", &fmt_chunk(&spec())).unwrap();
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(data);
+        write_chunk(&mut bytes, b"LIST", b"INFO").unwrap();
+        bytes
+    }
+
+    #[test]
+    fn reads_64_bit_sizes_from_ds64() {
+        let riff_size = 5 << 30;
+        let data_len = riff_size - 80;
+        let info = read_header(&mut Cursor::new(rf64(riff_size, data_len, &[]))).unwrap();
+        assert_eq!(info.data_offset, 80);
+        assert_eq!(info.data_len, data_len);
+        assert_eq!(info.file_len, riff_size + 8);
+    }
+
+    #[test]
+    fn wav_source_stops_at_the_ds64_data_length() {
+        use crate::source::{AudioSource, Chunk, WavSource};
+
+        let path = temp_path("rf64");
+        let data = [1i16, -1, 2, -2]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect::<Vec<_>>();
+        // 80 bytes of header, 8 of data and a 12-byte trailing chunk.
+        fs::write(&path, rf64(100 - 8, 8, &data)).unwrap();
+
+        let mut source = WavSource::open(&path).unwrap();
+        let mut samples = Vec::new();
+        while let Some(chunk) = source.read().unwrap() {
+            match chunk {
+                Chunk::Int(chunk) => samples.extend_from_slice(chunk),
+                Chunk::Float(_) => panic!("float samples from 16-bit WAV"),
+            }
+        }
+        assert_eq!(samples, [1, -1, 2, -2]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sizes_past_4_gib_switch_to_rf64() {
+        let mut file = Cursor::new(Vec::new());
+        let writer = WavWriter::new(&mut file, spec(), None).unwrap();
+        let data_size_pos = writer.data_size_pos;
+        drop(writer);
+        let header_len = data_size_pos + 4;
+
+        let data_len = 5 << 30;
+        let file_len = header_len + data_len;
+        write_sizes(&mut file, &spec(), file_len, data_size_pos, data_len).unwrap();
+        let bytes = file.into_inner();
+        assert_eq!(bytes.len() as u64, header_len);
+        assert_eq!(&bytes[..4], b"RF64");
+        assert_eq!(&bytes[12..16], b"ds64");
+        assert_eq!(read_u32(&bytes, data_size_pos as usize), u32::MAX);
+        assert_eq!(read_u64(&bytes, 36), data_len / 4);
+
+        let info = read_header(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(info.data_offset, header_len);
+        assert_eq!(info.data_len, data_len);
+        assert_eq!(info.file_len, file_len);
+    }
+
+    #[test]
+    fn sizes_below_4_gib_stay_riff() {
+        let mut file = Cursor::new(Vec::new());
+        let writer = WavWriter::new(&mut file, spec(), None).unwrap();
+        let data_size_pos = writer.data_size_pos;
+        drop(writer);
+
+        let data_len = u32::MAX as u64 - 100;
+        let file_len = data_size_pos + 4 + data_len;
+        write_sizes(&mut file, &spec(), file_len, data_size_pos, data_len).unwrap();
+        let bytes = file.into_inner();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(&bytes[12..16], b"JUNK");
+        assert_eq!(read_u32(&bytes, 4) as u64, file_len - 8);
+
+        let info = read_header(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(info.data_len, data_len);
+        assert_eq!(info.file_len, file_len);
+    }
+
+    #[test]
+    fn oversized_chunk_fails_without_allocating() {
+        let mut reader = Cursor::new(vec![0; 16]);
+        let err = read_body(&mut reader, u32::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}